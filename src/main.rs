@@ -9,17 +9,18 @@ use std::fs::{File, OpenOptions, read};
 use std::os::fd::AsRawFd;
 use std::io::{self, Read, Write, PipeWriter, PipeReader, BufRead, BufReader, ErrorKind, Cursor};
 use std::process::{Command, Stdio, exit};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::path::{Path, PathBuf};
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::CommandExt;
 use std::os::fd::{FromRawFd, RawFd};
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::str::Chars;
 use std::iter::Peekable;
 
 use regex::Regex;
 extern crate libc;
-use libc::{fork, waitpid, pid_t, WIFEXITED, WEXITSTATUS};
+use libc::{fork, waitpid, pid_t, WEXITSTATUS};
 use glob::{glob, Pattern};
 use tempfile::tempfile;
 
@@ -88,13 +89,10 @@ impl Val {
                 SYM => (&*(*self.var).name).to_string_lossy().parse().ok(),
                 CELL => None,
                 FAT => {
-                    if self.is_float() {
-                        match self.fat() {
-                            Fat::Float(x) => Some(*x as isize),
-                            _ => panic!()
-                        }
-                    } else {
-                        None
+                    match self.fat() {
+                        Fat::Float(x) => Some(*x as isize),
+                        Fat::Proc(x) => Some(x.pid as isize),
+                        _ => None
                     }
                 }
                 VAR => (&*(*self.sym).name).to_string_lossy().parse().ok(),
@@ -240,6 +238,20 @@ impl Val {
         }
     }
     #[inline(always)]
+    fn is_proc (&self) -> bool {
+        unsafe {
+            match self.id & TAG_MASK {
+                FAT => {
+                    let fat = self.copy().remove_tag(FAT);
+                    let result = matches!(&(*fat.fat).val, Fat::Proc(_));
+                    std::mem::forget(fat);
+                    result
+                }
+                _ => false
+            }
+        }
+    }
+    #[inline(always)]
     fn is_piper (&self) -> bool {
         unsafe {
             match self.id & TAG_MASK {
@@ -385,6 +397,8 @@ impl Val {
             Fat::PipeR(_) => "pipe",
             Fat::PipeW(_) => "pipe",
             Fat::Dict(_) => "dictionary",
+            Fat::Proc(_) => "process",
+            Fat::ProcSub(_) => "proc-sub",
             Fat::Nothing => "none",
         }
     }
@@ -531,6 +545,16 @@ impl Val {
         }
     }
     #[inline(always)]
+    fn move_buf(&self) -> Box<dyn BufRead + Send> {
+        if !self.is_buf() {
+            panic!();
+        }
+        match std::mem::replace(self.fat(), Fat::Buf(Box::new(io::empty()))) {
+            Fat::Buf(buf) => buf,
+            _ => panic!()
+        }
+    }
+    #[inline(always)]
     fn clone_piper(&self) -> PipeReader {
         if !self.is_piper() {
             panic!();
@@ -562,17 +586,34 @@ impl Val {
             _ => panic!()
         }
     }
-    fn to_stdio(&self, env: &mut Env) -> Result<Stdio, Exception> {
-        if self.is_file() {
+    fn to_stdio(&self, env: &mut Env, input: bool) -> Result<Stdio, Exception> {
+        if let Some(sub) = self.proc_sub() {
+            sub.pipe.to_stdio(env, input)
+        } else if self.is_file() {
             Ok(Stdio::from(self.clone_file()))
         } else if self.is_piper() {
             Ok(Stdio::from(self.clone_piper()))
         } else if self.is_pipew() {
             Ok(Stdio::from(self.clone_pipew()))
+        } else if input && (self.is_buf() || self.is_str() || self.is_sym()) {
+            Ok(Stdio::from(env.feed("shino", self)?.move_piper()))
         } else {
             Err(env.type_err("shino", self, "fd"))
         }
     }
+    fn raw_fd(&self) -> Option<RawFd> {
+        if let Some(sub) = self.proc_sub() {
+            sub.pipe.raw_fd()
+        } else if self.is_file() {
+            Some(self.file().as_raw_fd())
+        } else if self.is_piper() {
+            Some(self.piper().as_raw_fd())
+        } else if self.is_pipew() {
+            Some(self.pipew().as_raw_fd())
+        } else {
+            None
+        }
+    }
     #[inline(always)]
     fn captured(&self) -> &mut Val {
         if !self.is_captured() {
@@ -622,6 +663,22 @@ impl Val {
         }
     }
     #[inline(always)]
+    fn proc_sub(&self) -> Option<&ProcSub> {
+        if !self.is_fat() {
+            return None;
+        }
+        match self.fat() {
+            Fat::ProcSub(x) => Some(x),
+            _ => None
+        }
+    }
+    fn proc(&self) -> &mut Box<Proc> {
+        match self.fat() {
+            Fat::Proc(x) => x,
+            _ => panic!()
+        }
+    }
+    #[inline(always)]
     fn is_nil(&self) -> bool {
         NIL.with(|x| unsafe {
             self == x.get().unwrap()
@@ -663,7 +720,7 @@ impl Val {
             Ok(0)
         }
     }
-    fn new_buf(buf: Box<dyn BufRead>) -> Val {
+    fn new_buf(buf: Box<dyn BufRead + Send>) -> Val {
         unsafe {
             let result = Val::new();
             let tmp = std::mem::replace(&mut (*result.fat).val, Fat::Buf(buf));
@@ -766,6 +823,28 @@ impl From<PipeReader> for Val {
         }
     }
 }
+impl From<ProcSub> for Val {
+    fn from(sub: ProcSub) -> Self {
+        unsafe {
+            let result = Val::new();
+            let tmp = std::mem::replace(&mut (*result.fat).val, Fat::ProcSub(Box::new(sub)));
+            std::mem::forget(tmp);
+            (*result.fat).count = 1;
+            result.add_tag(FAT)
+        }
+    }
+}
+impl From<Proc> for Val {
+    fn from(proc: Proc) -> Self {
+        unsafe {
+            let result = Val::new();
+            let tmp = std::mem::replace(&mut (*result.fat).val, Fat::Proc(Box::new(proc)));
+            std::mem::forget(tmp);
+            (*result.fat).count = 1;
+            result.add_tag(FAT)
+        }
+    }
+}
 impl From<PipeWriter> for Val {
     fn from(w: PipeWriter) -> Self {
         unsafe {
@@ -784,7 +863,11 @@ impl TryFrom<Val> for isize {
         unsafe {
             match val.id & TAG_MASK {
                 SYM => (&*(*val.sym).name).to_string_lossy().parse().or_else(|_| Err(val)),
-                CELL|FAT => Err(val),
+                CELL => Err(val),
+                FAT => match val.fat() {
+                    Fat::Proc(x) => Ok(x.pid as isize),
+                    _ => Err(val),
+                }
                 VAR => (&*(*val.var).name).to_string_lossy().parse().or_else(|_| Err(val)),
                 _ => {
                     let result = val.num >> 1;
@@ -803,15 +886,10 @@ impl TryFrom<Val> for f64 {
             match val.id & TAG_MASK {
                 SYM => (&*(*val.sym).name).to_string_lossy().parse().or_else(|_| Err(val)),
                 CELL => Err(val),
-                FAT => {
-                    if val.is_float() {
-                        match val.fat() {
-                            Fat::Float(x) => Ok(*x),
-                            _ => panic!()
-                        }
-                    } else {
-                        Err(val)
-                    }
+                FAT => match val.fat() {
+                    Fat::Float(x) => Ok(*x),
+                    Fat::Proc(x) => Ok(x.pid as f64),
+                    _ => Err(val),
                 }
                 VAR => (&*(*val.var).name).to_string_lossy().parse().or_else(|_| Err(val)),
                 _ => {
@@ -944,6 +1022,8 @@ impl fmt::Display for Val {
                         Fat::PipeR(x) => write!(f, "{}", x.as_raw_fd()),
                         Fat::PipeW(x) => write!(f, "{}", x.as_raw_fd()),
                         Fat::Dict(x) => write!(f, "Dictionary"),
+                        Fat::Proc(x) => write!(f, "#<process {} {}>", x.pid, x.state()),
+                        Fat::ProcSub(x) => write!(f, "/dev/fd/{}", x.pipe.raw_fd().unwrap_or(-1)),
                         Fat::Nothing => write!(f, "Nothing"),
                     };
                     result
@@ -1083,7 +1163,6 @@ impl std::io::Write for Val {
         if self.is_file() {
             self.file().write(buf)
         } else if self.is_pipew() {
-            println!("a");
             self.pipew().write(buf)
         } else {
             Ok(0)
@@ -1134,15 +1213,78 @@ struct FatPtr {
 enum Fat {
     Captured(Val),
     Float(f64),
-    Buf(Box<dyn BufRead>),
+    Buf(Box<dyn BufRead + Send>),
     Chars(Box<dyn CharsAPI>),
     File(Box<File>),
     PipeR(Box<PipeReader>),
     PipeW(Box<PipeWriter>),
     Dict(Box<HashMap<PathBuf, Val>>),
+    Proc(Box<Proc>),
+    ProcSub(Box<ProcSub>),
     Nothing,
 }
 
+struct Proc {
+    pid: pid_t,
+    pipes: Vec<Val>,
+    status: Option<libc::c_int>,
+}
+impl Proc {
+    fn new(pid: pid_t, pipes: Vec<Val>) -> Proc {
+        Proc { pid, pipes, status: None }
+    }
+    fn state(&self) -> String {
+        let signaled = |sig| signal_name(sig)
+            .map_or_else(|| format!("signaled {}", sig), |name| format!("signaled {}", name));
+        if let Some(status) = self.status {
+            return if libc::WIFSIGNALED(status) {
+                signaled(libc::WTERMSIG(status))
+            } else {
+                format!("exited {}", WEXITSTATUS(status))
+            };
+        }
+        unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+            if libc::waitid(libc::P_PID, self.pid as libc::id_t, &mut info, flags) == -1 {
+                return "reaped".to_string();
+            }
+            match info.si_code {
+                libc::CLD_EXITED => format!("exited {}", info.si_status()),
+                libc::CLD_KILLED|libc::CLD_DUMPED => signaled(info.si_status()),
+                _ => "running".to_string(),
+            }
+        }
+    }
+}
+impl Drop for Proc {
+    fn drop(&mut self) {
+        self.pipes.clear();
+        if self.status.is_some() {
+            return;
+        }
+        let mut status: libc::c_int = 0;
+        let status = match unsafe { waitpid(self.pid, &mut status, libc::WNOHANG) } {
+            0 => None,
+            -1 => return,
+            _ => Some(status),
+        };
+        DETACHED.with(|detached| detached.borrow_mut().push((self.pid, status)));
+    }
+}
+struct ProcSub {
+    pid: pid_t,
+    pipe: Val,
+}
+impl Drop for ProcSub {
+    fn drop(&mut self) {
+        self.pipe = nil();
+        let mut status: libc::c_int = 0;
+        while unsafe { waitpid(self.pid, &mut status, 0) } == -1
+            && io::Error::last_os_error().kind() == ErrorKind::Interrupted {}
+    }
+}
+
 #[repr(C)]
 struct Sym {
     func: Val,
@@ -1212,6 +1354,34 @@ struct Env {
     set_val: Val,
     glob_regex: Regex,
     gensym_id: usize,
+    fds: Vec<(RawFd, Val)>,
+    coproc_fds: Vec<(RawFd, (u64, u64))>,
+    reaped: StdHashMap<pid_t, libc::c_int>,
+    traps: StdHashMap<String, Val>,
+    completers: StdHashMap<String, Val>,
+    jobs: Vec<Job>,
+    job_control: bool,
+    exec_last: bool,
+    argv0: Option<Val>,
+    exports: Vec<Val>,
+    // environment changes, None removing a variable; env::set_var is unsound while feeder threads run
+    env_overlay: StdHashMap<OsString, Option<OsString>>,
+    dir_stack: Vec<PathBuf>,
+    shell_pgid: pid_t,
+}
+#[derive(Clone, Copy, PartialEq)]
+enum JobState {
+    Running,
+    Stopped,
+    Done(i32),
+    Unknown,
+}
+struct Job {
+    id: usize,
+    pid: pid_t,
+    last: pid_t,
+    cmd: String,
+    state: JobState,
 }
 #[derive(Clone)]
 struct Symbols {
@@ -1245,10 +1415,18 @@ struct Symbols {
     encode_err: Val,
     parse_err: Val,
     zero_division_err: Val,
+    timeout_err: Val,
+    interrupt_err: Val,
     missing_values_err: Val,
     multi_done: Val,
     swap_done: Val,
     progn: Val,
+    pipeline: Val,
+    redirect: Val,
+    if_: Val,
+    spawn: Val,
+    expand: Val,
+    proc_sub: Val,
     mac: Val,
     unquote: Val,
     app_arg: Val,
@@ -1271,10 +1449,26 @@ impl Env {
         let _ = "with-handler".intern_func(catch);
         let _ = "shift".intern_func(shift);
         let _ = "spawn".intern_func(spawn);
+        let _ = "timeout".intern_func(timeout);
+        let _ = "limit".intern_func(limit);
+        let _ = "with-limits".intern_func(with_limits);
+        let _ = "parallel-each".intern_func(parallel_each);
+        let _ = "coproc".intern_func(coproc);
+        let _ = "capture".intern_func(capture);
+        let _ = "run".intern_func(run);
+        let _ = "argv0".intern_func(argv0);
         let _ = "wait-pid".intern_func(wait_pid);
+        let _ = "jobs".intern_func(jobs);
+        let _ = "fg".intern_func(fg);
+        let _ = "bg".intern_func(bg);
+        let _ = "disown".intern_func(disown);
+        let _ = "kill".intern_func(kill);
+        let _ = "signal-name".intern_func(signal_name_);
+        let _ = "signal-number".intern_func(signal_number_);
         let mval = "@".to_sym(nil.clone(), Val{func: mval}.add_tag(FUNC));
         let _ = "gensym".intern_func(gensym);
         let _ = "trap".intern_func(trap);
+        let _ = "complete".intern_func(complete);
         let _ = "macro-expand".intern_func(macro_expand);
         let _ = "eval".intern_func(eval);
         let _ = "fail".intern_func(fail);
@@ -1304,6 +1498,7 @@ impl Env {
         let _ = "is-number".intern_func(is_number);
         let _ = "is-chars".intern_func(is_chars);
         let _ = "is-file".intern_func(is_file);
+        let _ = "is-process".intern_func(is_process);
         let _ = "is-atom".intern_func(is_atom);
         let _ = "is-buffered".intern_func(is_buffered);
 
@@ -1329,9 +1524,21 @@ impl Env {
         let _ = "print".intern_func(print);
         let _ = "pipe".intern_func(pipe);
         let _ = "buf".intern_func(buf);
+        let _ = "close".intern_func(close);
         let _ = "chars".intern_func(chars);
         let _ = "open".intern_func(open);
         let _ = "env-var".intern_func(getenv);
+        let _ = "env-vars".intern_func(env_vars);
+        let _ = "env-unset".intern_func(env_unset);
+        let _ = "with-env".intern_func(with_env);
+        let _ = "cd".intern_func(cd);
+        let _ = "pwd".intern_func(pwd);
+        let _ = "pushd".intern_func(pushd);
+        let _ = "popd".intern_func(popd);
+        let _ = "dirs".intern_func(dirs);
+        let _ = "in-dir".intern_func(in_dir);
+        let _ = "export".intern_func(export);
+        let _ = "unexport".intern_func(unexport);
         let glob = "glob".to_sym(nil.clone(), Val{func: glob_at}.add_tag(FUNC));
 
         let std_in :Val = std::io::stdin().as_raw_fd().into();
@@ -1352,6 +1559,12 @@ impl Env {
             fn_: "fn".intern(),
             mac: "mac".intern(),
             progn: "do".intern_func(progn),
+            pipeline: "|".intern_func(pipeline),
+            redirect: "redirect".intern_func(redirect),
+            if_: "if".intern(),
+            spawn: "spawn".intern(),
+            expand: "expand".intern(),
+            proc_sub: "proc-sub".intern_func(proc_sub),
             cap: "cap".intern_func(cap),
             mval: mval.clone(),
 
@@ -1377,6 +1590,8 @@ impl Env {
             encode_err:"encode-error".intern(),
             parse_err:"parse-error".intern(),
             zero_division_err:"zero-division-error".intern(),
+            timeout_err:"timeout-error".intern(),
+            interrupt_err:"interrupt".intern(),
             multi_done: "multi_done".to_sym(nil.clone(), nil.clone()),
             swap_done: "swap_done".to_sym(nil.clone(), nil.clone()),
             unquote: "unquote".to_sym(nil.clone(), nil.clone()),
@@ -1391,6 +1606,19 @@ impl Env {
             rest_stack,
             set_val: nil.clone(),
             gensym_id: 0,
+            fds: Vec::new(),
+            coproc_fds: Vec::new(),
+            reaped: StdHashMap::new(),
+            traps: StdHashMap::new(),
+            completers: StdHashMap::new(),
+            jobs: Vec::new(),
+            job_control: false,
+            exec_last: false,
+            argv0: None,
+            exports: Vec::new(),
+            env_overlay: StdHashMap::new(),
+            dir_stack: Vec::new(),
+            shell_pgid: unsafe { libc::getpgrp() },
             sym,
         }
     }
@@ -1449,40 +1677,643 @@ impl Env {
         }
         Ok(old_stack_len)
     }
-    fn eval_cmd(&mut self, _: Mode, cmd: &Path, args: &Val) -> Result<bool, Exception> {
+    fn build_command(&mut self, cmd: &Path, args: &Val) -> Result<(Command, Vec<Val>), Exception> {
         let old_stack_len = self.eval_args(args)?;
 
         let mut command = Command::new(cmd);
-        for _ in old_stack_len..self.arg_stack.len() {
-            let v = self.arg_stack.pop().unwrap();
+        let args: Vec<Val> = self.arg_stack.drain(old_stack_len..).collect();
+        for v in &args {
             let s = v.to_path()
-                .or_else(|_|Err(self.type_err_to_str(&cmd.to_string_lossy(), &v)))?;
+                .map_err(|_| self.type_err_to_str(&cmd.to_string_lossy(), v))?;
             command.arg(&*s);
         }
+        let proc_subs: Vec<Val> = args.into_iter().filter(|v| v.proc_sub().is_some()).collect();
+
+        for (name, value) in &self.env_overlay {
+            match value {
+                Some(value) => command.env(name, value),
+                None => command.env_remove(name),
+            };
+        }
+        for (name, value) in self.exported_vars()? {
+            command.env(name, value);
+        }
+
+        if let Some(name) = self.argv0.clone() {
+            let name = name.to_path()
+                .map_err(|_| self.type_err_to_str("argv0", &name))?.into_owned();
+            command.arg0(name);
+        }
+
+        let fds: Vec<(RawFd, RawFd)> = self.fds.iter()
+            .filter_map(|(fd, val)| val.raw_fd().map(|src| (*fd, src)))
+            .chain(proc_subs.iter().filter_map(|val| val.raw_fd().map(|fd| (fd, fd))))
+            .collect();
+        unsafe {
+            command.pre_exec(move || {
+                close_on_exec_from(3);
+                for &(fd, src) in &fds {
+                    if fd == src {
+                        libc::fcntl(fd, libc::F_SETFD, 0);
+                    } else if libc::dup2(src, fd) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
 
         let std_in = self.sym.stdin.var().val.clone();
         let std_out = self.sym.stdout.var().val.clone();
         let std_err = self.sym.stderr.var().val.clone();
 
-        command.stdin(std_in.to_stdio(self)?)
-            .stdout(std_out.to_stdio(self)?)
-            .stderr(std_err.to_stdio(self)?).output();
-        match command.status() {
-            Ok(status) => {
-                match status.code() {
-                    Some(code) => {
-                        self.push((code as isize).into());
-                        Ok(code == 0)
+        command.stdin(std_in.to_stdio(self, true)?)
+            .stdout(std_out.to_stdio(self, false)?)
+            .stderr(std_err.to_stdio(self, false)?);
+        Ok((command, proc_subs))
+    }
+    fn var_os(&self, name: impl AsRef<OsStr>) -> Option<OsString> {
+        match self.env_overlay.get(name.as_ref()) {
+            Some(value) => value.clone(),
+            None => env::var_os(name),
+        }
+    }
+    fn set_var(&mut self, name: impl Into<OsString>, value: Option<impl Into<OsString>>) {
+        let _ = self.env_overlay.insert(name.into(), value.map(Into::into));
+    }
+    fn vars_os(&self) -> Vec<(OsString, OsString)> {
+        let mut vars: Vec<(OsString, OsString)> = env::vars_os()
+            .filter(|(name, _)| !self.env_overlay.contains_key(name)).collect();
+        vars.extend(self.env_overlay.iter()
+            .filter_map(|(name, value)| value.clone().map(|value| (name.clone(), value))));
+        vars
+    }
+    fn exported_vars(&mut self) -> Result<Vec<(PathBuf, PathBuf)>, Exception> {
+        let mut vars = vec![];
+        for var in self.exports.clone() {
+            let value = &var.var().val;
+            if value.is_nil() {
+                continue;
+            }
+            let value = value.to_path()
+                .map_err(|_| self.type_err_to_str("export", value))?.into_owned();
+            vars.push((unsafe { (*var.var().name).clone() }, value));
+        }
+        Ok(vars)
+    }
+    fn eval_cmd(&mut self, _: Mode, cmd: &Path, args: &Val) -> Result<bool, Exception> {
+        let exec_last = std::mem::take(&mut self.exec_last);
+        self.spawn_cmd(cmd, args, exec_last)
+    }
+    fn spawn_cmd(&mut self, cmd: &Path, args: &Val, exec_last: bool) -> Result<bool, Exception> {
+        let (mut command, _proc_subs) = self.build_command(cmd, args)?;
+        if exec_last && !self.traps.contains_key("exit") && FEEDERS.load(Ordering::SeqCst) == 0 {
+            let _ = self.sym.stdout.var().val.flush();
+            let _ = io::stdout().flush();
+            let e = command.exec();
+            return Err(self.other_err(self.sym.syscall_err.clone(),
+                format!("{}: detail={:?}", cmd.display(), e)));
+        }
+        if self.job_control {
+            command.process_group(0);
+            let ignored = self.ignored_signals();
+            unsafe {
+                command.pre_exec(move || {
+                    reset_job_signals(&ignored);
+                    Ok(())
+                });
+            }
+        }
+        match command.spawn() {
+            Ok(child) => self.wait_foreground(child.id() as pid_t, child.id() as pid_t,
+                cmd.to_string_lossy().into_owned(), false),
+            Err(e) => Err(self.other_err(self.sym.syscall_err.clone(),
+                format!("{}: detail={:?}", cmd.display(), e))),
+        }
+    }
+    fn set_exec_last(&mut self, ast: &Val) {
+        self.exec_last = ast.is_cell() && unsafe {
+            let cmd = ast.car();
+            cmd.id & TAG_MASK == SYM && (*cmd.sym).func == self.sym.nil
+        };
+    }
+    fn fork_eval<F: FnOnce(&mut Env)>(&mut self, name: &str, mode: Mode, ast: &Val, setup: F)
+        -> Result<pid_t, Exception>
+    {
+        match unsafe { fork() } {
+            -1 => Err(self.other_err(self.sym.syscall_err.clone(),
+                        format!("{}: failed to fork", name))),
+            0 => {
+                unsafe {
+                    libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+                }
+                self.reset_traps();
+                self.close_coproc_fds();
+                self.reaped.clear();
+                DETACHED.with(|detached| detached.borrow_mut().clear());
+                if self.job_control {
+                    self.job_control = false;
+                    self.jobs.clear();
+                    reset_job_signals(&self.ignored_signals());
+                }
+                setup(self);
+                let old_stack_len = self.arg_stack.len();
+                let result = self.eval(mode.for_special_form(), ast);
+                exit(self.exit_code(result, old_stack_len))
+            }
+            pid => Ok(pid),
+        }
+    }
+    fn exit_code(&mut self, result: Result<bool, Exception>, old_stack_len: usize) -> i32 {
+        match result {
+            Ok(true) => 0,
+            Ok(false) => {
+                self.arg_stack.truncate(old_stack_len + 1);
+                let n = if old_stack_len == self.arg_stack.len() {
+                    ONE
+                } else {
+                    self.arg_stack.pop().unwrap()
+                };
+                if n.is_num() {
+                    isize::try_from(n).unwrap() as i32
+                } else {
+                    1
+                }
+            }
+            _ => 1
+        }
+    }
+    fn run_trap(&mut self, code: &Val, args: &[Val]) -> Result<Val, Exception> {
+        let ret = self.sym.ret.var().val.clone();
+        let exec_last = std::mem::take(&mut self.exec_last);
+        let old_rest_stack_len = self.rest_stack.len();
+        for arg in args.iter().rev() {
+            self.rest_stack.push(arg.clone());
+        }
+        self.rest_stack.push((args.len() as isize).into());
+        let old_stack_len = self.arg_stack.len();
+        let result = self.eval(Mode::Single, code).map(|_| {
+            let val = if self.arg_stack.len() > old_stack_len {
+                self.arg_stack.pop().unwrap()
+            } else {
+                self.nil()
+            };
+            self.arg_stack.truncate(old_stack_len);
+            val
+        });
+        self.rest_stack.truncate(old_rest_stack_len);
+        self.sym.ret.var().val = ret;
+        self.exec_last = exec_last;
+        result
+    }
+    fn run_traps(&mut self) -> Result<(), Exception> {
+        let mut pending = PENDING_SIGNALS.swap(0, Ordering::SeqCst);
+        for &(name, sig) in SIGNALS {
+            if pending & (1 << sig) == 0 {
+                continue;
+            }
+            pending &= !(1 << sig);
+            let result = if let Some(code) = self.traps.get(name).cloned() {
+                self.run_trap(&code, &[name.intern()]).map(|_| ())
+            } else if sig == libc::SIGINT && self.job_control {
+                Err(self.other_err(self.sym.interrupt_err.clone(), "interrupted".to_string()))
+            } else {
+                Ok(())
+            };
+            if result.is_err() {
+                PENDING_SIGNALS.fetch_or(pending, Ordering::SeqCst);
+                return result;
+            }
+        }
+        Ok(())
+    }
+    fn trap_error(&mut self) {
+        let Some(code) = self.traps.get("error").cloned() else {
+            return;
+        };
+        let len = self.arg_stack.len();
+        let args = [self.arg_stack[len - 2].clone(), self.arg_stack[len - 1].clone()];
+        if self.run_trap(&code, &args).is_err() {
+            self.arg_stack.truncate(len);
+        }
+    }
+    fn trap_exit(&mut self) {
+        let Some(code) = self.traps.remove("exit") else {
+            return;
+        };
+        let len = self.arg_stack.len();
+        let _ = self.run_trap(&code, &[]);
+        self.arg_stack.truncate(len);
+    }
+    // a child must not hold coproc pipe ends or the coproc never sees EOF
+    fn close_coproc_fds(&mut self) {
+        let coproc_fds = std::mem::take(&mut self.coproc_fds);
+        if coproc_fds.is_empty() {
+            return;
+        }
+        let stdio = [&self.sym.stdin, &self.sym.stdout, &self.sym.stderr];
+        let used: Vec<RawFd> = stdio.iter().filter_map(|sym| sym.var().val.raw_fd())
+            .chain(self.fds.iter().filter_map(|(_, val)| val.raw_fd())).collect();
+        unsafe {
+            let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC);
+            if null == -1 {
+                return;
+            }
+            for (fd, id) in coproc_fds {
+                if !used.contains(&fd) && fd_identity(fd) == Some(id) && libc::dup2(null, fd) != -1 {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+            }
+            libc::close(null);
+        }
+    }
+    fn ignored_signals(&self) -> Vec<libc::c_int> {
+        self.traps.iter().filter(|(_, code)| code.is_nil())
+            .filter_map(|(name, _)| signal_number(name)).collect()
+    }
+    fn reset_traps(&mut self) {
+        for (name, code) in std::mem::take(&mut self.traps) {
+            let Some(sig) = signal_number(&name) else {
+                continue;
+            };
+            if code.is_nil() {
+                self.traps.insert(name, code);
+            } else {
+                unsafe {
+                    libc::signal(sig, libc::SIG_DFL);
+                }
+            }
+        }
+        PENDING_SIGNALS.store(0, Ordering::SeqCst);
+    }
+    fn logical_pwd(&mut self, name: &str) -> Result<PathBuf, Exception> {
+        use std::os::unix::fs::MetadataExt;
+        let same = |a: &std::fs::Metadata, b: &std::fs::Metadata| a.dev() == b.dev() && a.ino() == b.ino();
+        if let Some(pwd) = self.var_os("PWD").map(PathBuf::from) {
+            if let (true, Ok(a), Ok(b)) = (pwd.is_absolute(), std::fs::metadata(&pwd), std::fs::metadata(".")) {
+                if same(&a, &b) {
+                    return Ok(pwd);
+                }
+            }
+        }
+        env::current_dir().map_err(|e| self.other_err(self.sym.syscall_err.clone(),
+            format!("{}: failed to get the current directory: detail={}", name, e)))
+    }
+    fn change_dir(&mut self, name: &str, dir: &Path) -> Result<PathBuf, Exception> {
+        let old = self.logical_pwd(name)?;
+        let mut logical = PathBuf::new();
+        for c in old.join(dir).components() {
+            match c {
+                std::path::Component::ParentDir => {
+                    let _ = logical.pop();
+                }
+                std::path::Component::CurDir => {}
+                c => logical.push(c),
+            }
+        }
+        let new = match env::set_current_dir(&logical) {
+            Ok(()) => logical,
+            Err(_) => match env::set_current_dir(dir).and_then(|_| env::current_dir()) {
+                Ok(physical) => physical,
+                Err(e) => return Err(self.other_err(self.sym.syscall_err.clone(),
+                    format!("{}: {}: detail={}", name, dir.display(), e))),
+            }
+        };
+        self.set_var("OLDPWD", Some(&old));
+        self.set_var("PWD", Some(&new));
+        Ok(new)
+    }
+    fn init_job_control(&mut self) {
+        unsafe {
+            if libc::isatty(0) == 0 {
+                return;
+            }
+            while libc::tcgetpgrp(0) != libc::getpgrp() {
+                libc::kill(-libc::getpgrp(), libc::SIGTTIN);
+            }
+            for sig in JOB_SIGNALS {
+                libc::signal(sig, job_signal_action(sig));
+            }
+            let pid = libc::getpid();
+            libc::setpgid(pid, pid);
+            libc::tcsetpgrp(0, pid);
+            self.shell_pgid = pid;
+        }
+        self.job_control = true;
+    }
+    fn add_job(&mut self, pid: pid_t, last: pid_t, cmd: String, state: JobState) -> usize {
+        let id = self.jobs.last().map_or(1, |job| job.id + 1);
+        self.jobs.push(Job { id, pid, last, cmd, state });
+        id
+    }
+    fn reap_detached(&mut self) {
+        let detached = DETACHED.with(|detached| std::mem::take(&mut *detached.borrow_mut()));
+        for (pid, status) in detached {
+            let status = status.or_else(|| {
+                let mut status: libc::c_int = 0;
+                match unsafe { waitpid(pid, &mut status, libc::WNOHANG) } {
+                    0 => {
+                        DETACHED.with(|detached| detached.borrow_mut().push((pid, None)));
+                        None
                     }
-                    None => Err(self.other_err(self.sym.syscall_err.clone(),
-                                "unknown error code".to_string()))
+                    -1 => None,
+                    _ => Some(status),
+                }
+            });
+            if let Some(status) = status {
+                if self.jobs.iter().any(|job| job.pid == pid) {
+                    let _ = self.reaped.insert(pid, status);
                 }
             }
-            Err(e) => {
-                Err(self.other_err(self.sym.syscall_err.clone(),
-                    format!("{}: detail={:?}", cmd.display(), e)))
+        }
+    }
+    fn update_jobs(&mut self) {
+        self.reap_detached();
+        for job in self.jobs.iter_mut() {
+            if matches!(job.state, JobState::Done(_) | JobState::Unknown) {
+                continue;
+            }
+            let group = job.last != job.pid;
+            if !group {
+                if let Some(status) = self.reaped.remove(&job.pid) {
+                    job.state = JobState::Done(exit_status(status));
+                    continue;
+                }
+            }
+            let target = if group { -job.pid } else { job.pid };
+            loop {
+                let mut status: libc::c_int = 0;
+                let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+                match unsafe { waitpid(target, &mut status, flags) } {
+                    0 => break,
+                    -1 => {
+                        job.state = self.reaped.remove(&job.last)
+                            .map_or(JobState::Unknown, |status| JobState::Done(exit_status(status)));
+                        break;
+                    }
+                    _ if libc::WIFSTOPPED(status) => job.state = JobState::Stopped,
+                    _ if libc::WIFCONTINUED(status) => job.state = JobState::Running,
+                    pid if pid == job.last && !group => {
+                        job.state = JobState::Done(exit_status(status));
+                        break;
+                    }
+                    pid if pid == job.last => {
+                        let _ = self.reaped.insert(pid, status);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    fn find_job(&mut self, name: &str, spec: Option<&Val>) -> Result<usize, Exception> {
+        let spec = match spec {
+            Some(v) => v.to_str()
+                .map_err(|_| self.type_err_to_str(name, v))?.into_owned(),
+            None => "%%".to_string(),
+        };
+        let index = match &*spec {
+            "%%"|"%+"|"%" => self.jobs.len().checked_sub(1),
+            "%-" => self.jobs.len().checked_sub(2),
+            _ => match spec.strip_prefix('%') {
+                Some(n) => n.parse::<usize>().ok()
+                    .and_then(|n| self.jobs.iter().position(|job| job.id == n)),
+                None => spec.parse::<pid_t>().ok()
+                    .and_then(|pid| self.jobs.iter().position(|job| job.pid == pid)),
+            }
+        };
+        index.ok_or_else(|| self.other_err(self.sym.arg_err.clone(),
+            format!("{}: {}: no such job", name, spec)))
+    }
+    fn reap(&mut self, pids: Option<&[pid_t]>, nohang: bool) -> Result<Option<(pid_t, libc::c_int)>, Exception> {
+        self.reap_detached();
+        let wanted = |pid: &pid_t| pids.is_none_or(|pids| pids.contains(pid));
+        if let Some(&pid) = self.reaped.keys().find(|pid| wanted(pid)) {
+            return Ok(self.reaped.remove(&pid).map(|status| (pid, status)));
+        }
+        let target = match pids {
+            Some(&[pid]) => pid,
+            _ => -1,
+        };
+        loop {
+            let mut status: libc::c_int = 0;
+            let flags = if nohang { libc::WNOHANG } else { 0 };
+            match unsafe { waitpid(target, &mut status, flags) } {
+                0 => return Ok(None),
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != ErrorKind::Interrupted {
+                        return Err(self.other_err(self.sym.syscall_err.clone(),
+                            format!("wait-pid: failed to wait {}: detail={}", target, e)));
+                    }
+                }
+                pid if wanted(&pid) => return Ok(Some((pid, status))),
+                pid => {
+                    let _ = self.reaped.insert(pid, status);
+                }
+            }
+        }
+    }
+    fn wait_foreground(&mut self, pid: pid_t, last: pid_t, cmd: String, cont: bool) -> Result<bool, Exception> {
+        unsafe {
+            if self.job_control {
+                libc::tcsetpgrp(0, pid);
+            }
+            if cont {
+                libc::kill(-pid, libc::SIGCONT);
+            }
+        }
+        let target = if self.job_control { -pid } else { last };
+        let mut status: libc::c_int = 0;
+        let mut last_status = self.reaped.remove(&last);
+        let mut stopped = None;
+        let mut error = None;
+        loop {
+            let ret = unsafe { waitpid(target, &mut status, libc::WUNTRACED) };
+            match ret {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == ErrorKind::Interrupted {
+                        continue;
+                    }
+                    if last_status.is_none() {
+                        error = Some(e);
+                    }
+                    break;
+                }
+                _ if libc::WIFSTOPPED(status) => {
+                    stopped = Some(status);
+                    break;
+                }
+                ret if ret == last => {
+                    last_status = Some(status);
+                    if target == last {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if self.job_control {
+            unsafe {
+                libc::tcsetpgrp(0, self.shell_pgid);
+            }
+        }
+        if let Some(e) = error {
+            return Err(self.other_err(self.sym.syscall_err.clone(),
+                format!("{}: failed to wait {}: detail={}", cmd, last, e)));
+        }
+
+        let index = self.jobs.iter().position(|job| job.pid == pid);
+        if let Some(status) = stopped {
+            if let Some(status) = last_status {
+                let _ = self.reaped.insert(last, status);
+            }
+            let id = match index {
+                Some(i) => {
+                    self.jobs[i].state = JobState::Stopped;
+                    self.jobs[i].id
+                }
+                None => self.add_job(pid, last, cmd.clone(), JobState::Stopped),
+            };
+            eprintln!("[{}]+  Stopped    {}", id, cmd);
+            self.push(((128 + libc::WSTOPSIG(status)) as isize).into());
+            return Ok(false);
+        }
+        if let Some(i) = index {
+            self.jobs.remove(i);
+        }
+        let code = exit_status(last_status.unwrap());
+        self.push((code as isize).into());
+        Ok(code == 0)
+    }
+    fn wait_children(&mut self, pids: &[pid_t]) {
+        for &pid in pids {
+            let mut status: libc::c_int = 0;
+            while unsafe { waitpid(pid, &mut status, 0) } == -1
+                && io::Error::last_os_error().kind() == ErrorKind::Interrupted {}
+        }
+    }
+    fn get_fd(&self, fd: RawFd) -> Option<Val> {
+        match fd {
+            0 => Some(self.sym.stdin.var().val.clone()),
+            1 => Some(self.sym.stdout.var().val.clone()),
+            2 => Some(self.sym.stderr.var().val.clone()),
+            _ => {
+                if let Some((_, val)) = self.fds.iter().rev().find(|(n, _)| *n == fd) {
+                    Some(val.clone())
+                } else if fd > 2 && unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1 {
+                    Some(fd.into())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+    fn set_fd(&mut self, fd: RawFd, val: Val) {
+        match fd {
+            0 => self.sym.stdin.var().val = val,
+            1 => self.sym.stdout.var().val = val,
+            2 => self.sym.stderr.var().val = val,
+            _ => self.fds.push((fd, val)),
+        }
+    }
+    fn feed(&mut self, name: &str, val: &Val) -> Result<Val, Exception> {
+        let mut src: Box<dyn Read + Send> = if val.is_buf() {
+            Box::new(val.move_buf())
+        } else {
+            let s = val.to_path()
+                .map_err(|_| self.type_err_to_str(name, val))?;
+            Box::new(Cursor::new(s.into_owned().into_os_string().into_vec()))
+        };
+        let (r, mut w) = std::io::pipe().map_err(|e| self.other_err(self.sym.syscall_err.clone(),
+            format!("{}: failed to create pipe: detail={}", name, e)))?;
+        FEEDERS.fetch_add(1, Ordering::SeqCst);
+        std::thread::spawn(move || {
+            let _ = io::copy(&mut src, &mut w);
+            FEEDERS.fetch_sub(1, Ordering::SeqCst);
+        });
+        Ok(r.into())
+    }
+    fn redirect_fd(&mut self, spec: &Val, proc_subs: &mut Vec<Val>) -> Result<(), Exception> {
+        let mut spec = spec;
+        let (Some(fd), Some(op), Some(target), None) = (spec.next(), spec.next(), spec.next(), spec.next()) else {
+            return Err(self.other_err(self.sym.arg_err.clone(),
+                "redirect: malformed redirection".to_string()));
+        };
+        let fd = match isize::try_from(fd.clone()) {
+            Ok(n) if n >= 0 => n,
+            _ => return Err(self.type_err_conv("redirect", fd)),
+        };
+        if op.to_str().unwrap_or_default() == "<<" {
+            let mut body = OsString::new();
+            for part in target {
+                self.eval(Mode::Single, part)?;
+                let v = self.arg_stack.pop().unwrap();
+                let s = v.to_path()
+                    .map_err(|_| self.type_err_to_str("redirect", &v))?;
+                body.push(s.as_os_str());
             }
+            let val = self.feed("redirect", &PathBuf::from(body).to_str())?;
+            self.set_fd(fd as RawFd, val);
+            return Ok(());
+        }
+
+        let old_stack_len = self.arg_stack.len();
+        let _ = self.eval(Mode::None, target)?;
+        if self.arg_stack.len() - old_stack_len != 1 {
+            let given = self.arg_stack.len() - old_stack_len;
+            self.arg_stack.truncate(old_stack_len);
+            return Err(self.argument_err("redirect", given, "1"));
         }
+        let target = self.arg_stack.pop().unwrap();
+
+        let op = op.to_str().unwrap_or_default().into_owned();
+        let val = match &*op {
+            "<<<" if target.is_buf() => self.feed("redirect", &target)?,
+            "<<<" => {
+                let mut s = target.to_path()
+                    .map_err(|_| self.type_err_to_str("redirect", &target))?.into_owned();
+                s.as_mut_os_string().push("\n");
+                self.feed("redirect", &s.to_str())?
+            }
+            ">&"|"<&" => {
+                let Ok(src) = isize::try_from(target.clone()) else {
+                    return Err(self.type_err_conv("redirect", &target));
+                };
+                match self.get_fd(src as RawFd) {
+                    Some(val) => val,
+                    None => return Err(self.other_err(self.sym.syscall_err.clone(),
+                                format!("redirect: {}: bad file descriptor", src))),
+                }
+            }
+            ">"|">>"|"<"|"<>" if target.proc_sub().is_some() => {
+                let pipe = target.proc_sub().unwrap().pipe.clone();
+                proc_subs.push(target);
+                pipe
+            }
+            ">"|">>"|"<"|"<>" if target.raw_fd().is_some() => target,
+            ">"|">>"|"<"|"<>" if target.is_proc() => return Err(self.type_err("redirect", &target, "path or file")),
+            ">"|">>"|"<"|"<>" => {
+                let mut options = OpenOptions::new();
+                let _ = match &*op {
+                    ">" => options.write(true).create(true).truncate(true),
+                    ">>" => options.append(true).create(true),
+                    "<" => options.read(true),
+                    _ => options.read(true).write(true).create(true),
+                };
+                let path = target.to_path()
+                    .map_err(|_| self.type_err_to_str("redirect", &target))?;
+                match options.open(&path) {
+                    Ok(f) => f.into(),
+                    Err(e) => return Err(self.other_err(self.sym.syscall_err.clone(),
+                        format!("redirect: failed to open {}: detail={}", path.display(), e))),
+                }
+            }
+            _ => return Err(self.other_err(self.sym.arg_err.clone(),
+                    format!("redirect: {}: unknown operator", op))),
+        };
+        self.set_fd(fd as RawFd, val);
+        Ok(())
     }
     #[inline(always)]
     fn stack_to_list(&mut self, mode: Mode, stack_idx: usize) {
@@ -1685,6 +2516,9 @@ impl Env {
     #[inline(always)]
     fn eval(&mut self, mode: Mode, ast: &Val) -> Result<bool, Exception> {
         //println!("#eval enter: {}", ast);
+        if PENDING_SIGNALS.load(Ordering::Relaxed) != 0 {
+            self.run_traps()?;
+        }
         let result = unsafe {
             match ast.id & TAG_MASK {
                 VAR => {
@@ -1957,11 +2791,86 @@ impl ToNamedObj for &str {
 
 
 static POOL_SIZE: AtomicUsize = AtomicUsize::new(1024);
+// writer threads feeding stdin; the interpreter must not exec while any is running
+static FEEDERS: AtomicUsize = AtomicUsize::new(0);
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("SIGHUP", libc::SIGHUP), ("SIGINT", libc::SIGINT), ("SIGQUIT", libc::SIGQUIT),
+    ("SIGILL", libc::SIGILL), ("SIGTRAP", libc::SIGTRAP), ("SIGABRT", libc::SIGABRT),
+    ("SIGBUS", libc::SIGBUS), ("SIGFPE", libc::SIGFPE), ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1), ("SIGSEGV", libc::SIGSEGV), ("SIGUSR2", libc::SIGUSR2),
+    ("SIGPIPE", libc::SIGPIPE), ("SIGALRM", libc::SIGALRM), ("SIGTERM", libc::SIGTERM),
+    ("SIGCHLD", libc::SIGCHLD), ("SIGCONT", libc::SIGCONT), ("SIGSTOP", libc::SIGSTOP),
+    ("SIGTSTP", libc::SIGTSTP), ("SIGTTIN", libc::SIGTTIN), ("SIGTTOU", libc::SIGTTOU),
+    ("SIGURG", libc::SIGURG), ("SIGXCPU", libc::SIGXCPU), ("SIGXFSZ", libc::SIGXFSZ),
+    ("SIGVTALRM", libc::SIGVTALRM), ("SIGPROF", libc::SIGPROF), ("SIGWINCH", libc::SIGWINCH),
+    ("SIGIO", libc::SIGIO), ("SIGSYS", libc::SIGSYS),
+];
+fn close_on_exec_from(low: RawFd) {
+    unsafe {
+        let flags = libc::CLOSE_RANGE_CLOEXEC as libc::c_long;
+        if libc::syscall(libc::SYS_close_range, low, libc::c_uint::MAX, flags) == 0 {
+            return;
+        }
+        let high = libc::sysconf(libc::_SC_OPEN_MAX).clamp(low as libc::c_long, 65536) as RawFd;
+        for fd in low..high {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags != -1 {
+                libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+            }
+        }
+    }
+}
+const JOB_SIGNALS: [libc::c_int; 5] = [libc::SIGINT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU, libc::SIGQUIT];
+fn fd_identity(fd: RawFd) -> Option<(u64, u64)> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+        return None;
+    }
+    Some((stat.st_dev as u64, stat.st_ino as u64))
+}
+fn reset_job_signals(ignored: &[libc::c_int]) {
+    for sig in JOB_SIGNALS {
+        if !ignored.contains(&sig) {
+            unsafe {
+                libc::signal(sig, libc::SIG_DFL);
+            }
+        }
+    }
+}
+fn job_signal_action(sig: libc::c_int) -> libc::sighandler_t {
+    if sig == libc::SIGINT {
+        queue_signal as *const () as libc::sighandler_t
+    } else {
+        libc::SIG_IGN
+    }
+}
+fn exit_status(status: libc::c_int) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        WEXITSTATUS(status)
+    }
+}
+extern "C" fn queue_signal(sig: libc::c_int) {
+    PENDING_SIGNALS.fetch_or(1 << sig, Ordering::SeqCst);
+}
+fn signal_number(name: &str) -> Option<libc::c_int> {
+    if let Ok(n) = name.parse::<libc::c_int>() {
+        return SIGNALS.iter().find(|(_, sig)| *sig == n).map(|(_, sig)| *sig);
+    }
+    let name = name.strip_prefix("SIG").unwrap_or(name);
+    SIGNALS.iter().find(|(s, _)| &s[3..] == name).map(|(_, sig)| *sig)
+}
+fn signal_name(sig: libc::c_int) -> Option<&'static str> {
+    SIGNALS.iter().find(|(_, n)| *n == sig).map(|(name, _)| *name)
+}
 thread_local!(
     static SYM_TABLE: RefCell<StdHashMap<PathBuf, Val>> = RefCell::new(StdHashMap::new());
     static NEXT_CELL: Cell<*mut Mem> = Cell::new(ptr::null_mut());
     static POOL_LIST: RefCell<Option<Box<Pool>>> = RefCell::new(None);
     static NIL: OnceCell<Val> = OnceCell::new();
+    static DETACHED: RefCell<Vec<(pid_t, Option<libc::c_int>)>> = const { RefCell::new(Vec::new()) };
 );
 #[inline(always)]
 fn nil() -> Val {
@@ -1972,7 +2881,9 @@ struct PeekableReader<'a, R: std::io::Read> {
     reader: BufReader<R>,
     iter: Peekable<Chars<'a>>,
     buf: String,
-    line: usize
+    line: usize,
+    newline_sep: bool,
+    standalone: bool,
 }
 #[derive(Debug)]
 enum ParseErr {
@@ -1994,12 +2905,199 @@ impl fmt::Display for ParseErr {
     }
 }
 type Parsed<T> = Result<Option<T>, ParseErr>;
+enum CommandOperator {
+    Pipe,
+    And,
+    Or,
+    Seq,
+    Background(Option<Val>),
+    End,
+}
 impl<'a, R: std::io::Read> PeekableReader<'a, R> {
     fn new(reader: R) -> Self {
         Self {
             reader: BufReader::new(reader), buf: "".to_string(),
-            iter: "".chars().peekable(), line: 1
+            iter: "".chars().peekable(), line: 1,
+            newline_sep: false, standalone: false
+        }
+    }
+    fn parse_block(&mut self, env: &Env, close: char, newline_sep: bool) -> Parsed<Val> {
+        let outer = std::mem::replace(&mut self.newline_sep, newline_sep);
+        let result = self.parse_list(env);
+        self.newline_sep = outer;
+        self.skip_brank();
+        match self.peek()? {
+            Some(c) if c == close => {
+                let _ = self.next();
+                result
+            }
+            _ => self.syntax_err(),
+        }
+    }
+    fn parse_value_operator(&mut self) -> Parsed<Val> {
+        let mut lookahead = self.iter.clone();
+        let mut len = 0;
+        while let Some(' '|'\t') = lookahead.peek() {
+            let _ = lookahead.next();
+            len += 1;
+        }
+        if lookahead.next() != Some('`') {
+            return Ok(None);
+        }
+        let mut name = "".to_string();
+        loop {
+            match lookahead.next() {
+                Some('`') if !name.is_empty() => break,
+                Some(c) if c != '`' && !c.is_whitespace() => name.push(c),
+                _ => return Ok(None),
+            }
+        }
+        for _ in 0..len + name.chars().count() + 2 {
+            let _ = self.next()?;
+        }
+        Ok(Some(name.intern()))
+    }
+    fn parse_infix(&mut self, env: &Env, first: Val) -> Parsed<Val> {
+        fn precedence(op: &Val) -> usize {
+            match &*op.to_str().unwrap_or_default() {
+                "*"|"/"|"%" => 3,
+                "+"|"-" => 2,
+                "=="|"="|"<"|"<="|">"|">=" => 1,
+                _ => 0,
+            }
+        }
+        fn reduce(env: &Env, out: &mut Vec<(Val, Option<Val>)>, op: Val) {
+            let (rhs, _) = out.pop().unwrap();
+            let (lhs, lhs_op) = out.pop().unwrap();
+            let args = cons(rhs, env.nil());
+            let expr = if lhs_op.as_ref() == Some(&op) {
+                let mut last = &lhs;
+                while last.cdr().is_cell() {
+                    last = last.cdr();
+                }
+                *last.cdr_mut() = args;
+                lhs
+            } else {
+                cons(op.clone(), cons(lhs, args))
+            };
+            out.push((expr, Some(op)));
+        }
+
+        let mut out = vec![(first, None)];
+        let mut ops: Vec<Val> = vec![];
+        while let Some(op) = self.parse_value_operator()? {
+            self.skip_brank();
+            let Some(operand) = self.parse_word(env)? else {
+                return self.syntax_err();
+            };
+            while ops.last().is_some_and(|top| precedence(top) >= precedence(&op)) {
+                reduce(env, &mut out, ops.pop().unwrap());
+            }
+            ops.push(op);
+            out.push((operand, None));
+        }
+        if out.len() == 1 {
+            return Ok(out.pop().map(|(val, _)| val));
+        }
+        while let Some(op) = ops.pop() {
+            reduce(env, &mut out, op);
+        }
+        self.standalone = true;
+        Ok(out.pop().map(|(val, _)| val))
+    }
+    fn parse_word(&mut self, env: &Env) -> Parsed<Val> {
+        let Some(first) = self.parse(env)? else {
+            return Ok(None);
+        };
+        let mut values = vec![];
+        loop {
+            match self.peek()? {
+                Some(c) if !c.is_ascii_whitespace()
+                    && !matches!(c, ')'|'|'|'&'|'{'|'}'|'>'|'<'|';'|'`') => {}
+                _ => break
+            }
+            let Some(value) = self.parse(env)? else {
+                break;
+            };
+            values.push(value);
+        }
+        if values.is_empty() {
+            return Ok(Some(first));
+        }
+        let mut list = env.nil();
+        while let Some(value) = values.pop() {
+            list = cons(value, list);
+        }
+        Ok(Some(cons(env.sym.expand.clone(), cons(first, list))))
+    }
+    fn skip_line_continuation(&mut self) {
+        loop {
+            self.skip_if(|c|c == ' ' || c == '\t');
+            let mut lookahead = self.iter.clone();
+            if lookahead.next() == Some('\\') && lookahead.next() == Some('\n') {
+                let _ = self.next();
+                let _ = self.next();
+            } else {
+                break;
+            }
+        }
+    }
+    fn at_proc_sub(&mut self) -> Result<bool, ParseErr> {
+        if !matches!(self.peek()?, Some('<'|'>')) {
+            return Ok(false);
+        }
+        let mut ahead = self.iter.clone();
+        let _ = ahead.next();
+        Ok(ahead.peek() == Some(&'('))
+    }
+    fn parse_heredoc(&mut self, env: &Env) -> Parsed<Val> {
+        let strip_tabs = self.peek()? == Some('-');
+        if strip_tabs {
+            let _ = self.next();
+        }
+        self.skip_if(|c|c == ' ' || c == '\t');
+        let (tag, expand) = match self.peek()? {
+            Some('\'') => {
+                let Some(tag) = self.parse(env)? else {
+                    return Ok(None);
+                };
+                (tag.to_str().unwrap_or_default().into_owned(), false)
+            }
+            _ => {
+                let mut tag = "".to_string();
+                while let Some(c) = self.peek()? {
+                    if c.is_ascii_whitespace() || ")|&;<>".contains(c) {
+                        break;
+                    }
+                    tag.push(c);
+                    let _ = self.next();
+                }
+                (tag, true)
+            }
+        };
+        if tag.is_empty() {
+            return Ok(None);
+        }
+
+        let mut body = "".to_string();
+        loop {
+            let mut line = "".to_string();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Err(ParseErr::Other(self.line,
+                            format!("here-document delimited by `{}' is not terminated", tag))),
+                Ok(_) => self.line += 1,
+                Err(e) => return Err(ParseErr::Read(e)),
+            }
+            let line = if strip_tabs { line.trim_start_matches('\t') } else { &line };
+            if line.trim_end_matches('\n') == tag {
+                break;
+            }
+            body.push_str(line);
         }
+        if !expand {
+            return Ok(Some(cons(body.to_str(), env.nil())));
+        }
+        Ok(Some(heredoc_parts(env, &body)))
     }
     fn syntax_err<T>(&mut self) -> Parsed<T> {
         match self.peek() {
@@ -2028,6 +3126,53 @@ impl<'a, R: std::io::Read> PeekableReader<'a, R> {
         }
     }
 }
+fn heredoc_parts(env: &Env, body: &str) -> Val {
+    let mut parts = vec![];
+    let mut text = "".to_string();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => {
+                text.push('$');
+                let _ = chars.next();
+            }
+            '$' => {
+                let mut name = "".to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_whitespace() || c.is_ascii_control()
+                        || (c.is_ascii_punctuation() && !"-_?".contains(c)) {
+                        break;
+                    }
+                    name.push(c);
+                    let _ = chars.next();
+                }
+                if name.is_empty() {
+                    text.push('$');
+                    continue;
+                }
+                if chars.peek() == Some(&'^') {
+                    let _ = chars.next();
+                }
+                if !text.is_empty() {
+                    parts.push(std::mem::take(&mut text).to_str());
+                }
+                parts.push(match name.parse::<isize>() {
+                    Ok(n) => cons(env.sym.arg.clone(), cons(n.into(), env.nil())),
+                    Err(_) => name.to_var(),
+                });
+            }
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(text.to_str());
+    }
+    let mut list = env.nil();
+    for part in parts.into_iter().rev() {
+        list = cons(part, list);
+    }
+    list
+}
 trait CharsAPI {
     fn peek(&mut self) -> Parsed<char>;
     fn next(&mut self) -> Parsed<char>;
@@ -2035,6 +3180,8 @@ trait CharsAPI {
     fn skip_brank(&mut self);
     fn parse(&mut self, env: &Env) -> Parsed<Val>;
     fn parse_list(&mut self, env: &Env) -> Parsed<Val>;
+    fn parse_command(&mut self, env: &Env, first: Option<Val>) -> Parsed<(Val, CommandOperator)>;
+    fn parse_redirect(&mut self, env: &Env, fd: Option<Val>) -> Parsed<Val>;
     fn line(&self) -> usize;
 }
 impl<'a, R: std::io::Read> CharsAPI for PeekableReader<'a, R> {
@@ -2094,20 +3241,206 @@ impl<'a, R: std::io::Read> CharsAPI for PeekableReader<'a, R> {
         }
     }
     fn parse_list(&mut self, env: &Env) -> Parsed<Val> {
-        self.skip_brank();
-        match self.parse(env)? {
-            Some(car) => Ok(Some(cons(car, self.parse_list(env)?.unwrap()))),
-            _ => {
-                match self.peek()? {
-                    Some(c) if c == '&' => {
+        let mut commands = vec![];
+        let mut and_or: Option<(Val, CommandOperator)> = None;
+        let mut stages = vec![];
+        let mut first = None;
+        loop {
+            let Some((command, op)) = self.parse_command(env, first.take())? else {
+                return Ok(None);
+            };
+            if command.is_nil() {
+                let trailing = matches!(op, CommandOperator::End)
+                    && stages.is_empty() && and_or.is_none();
+                if !trailing {
+                    return self.syntax_err();
+                }
+                if commands.is_empty() {
+                    return Ok(Some(command));
+                }
+                break;
+            }
+            stages.push(command);
+            if let CommandOperator::Pipe = op {
+                continue;
+            }
+
+            let mut pipeline = stages.pop().unwrap();
+            if !stages.is_empty() {
+                let mut list = cons(pipeline, env.nil());
+                while let Some(stage) = stages.pop() {
+                    list = cons(stage, list);
+                }
+                pipeline = cons(env.sym.pipeline.clone(), list);
+            }
+            if let Some((cond, logic)) = and_or.take() {
+                let then = cons(pipeline, env.nil());
+                let body = match logic {
+                    CommandOperator::Or => cons("?".to_var(), then),
+                    _ => then,
+                };
+                pipeline = cons(env.sym.if_.clone(), cons(cond, body));
+            }
+
+            match op {
+                CommandOperator::And|CommandOperator::Or => and_or = Some((pipeline, op)),
+                CommandOperator::Seq => commands.push(pipeline),
+                CommandOperator::Background(word) => {
+                    commands.push(cons(env.sym.spawn.clone(), cons(pipeline, env.nil())));
+                    first = word;
+                }
+                _ => {
+                    commands.push(pipeline);
+                    break;
+                }
+            }
+        }
+        if commands.len() == 1 {
+            return Ok(commands.pop());
+        }
+        let mut list = env.nil();
+        while let Some(command) = commands.pop() {
+            list = cons(command, list);
+        }
+        Ok(Some(cons(env.sym.progn.clone(), list)))
+    }
+    fn parse_command(&mut self, env: &Env, first: Option<Val>) -> Parsed<(Val, CommandOperator)> {
+        let mut standalone = first.is_some() && std::mem::take(&mut self.standalone);
+        let mut words: Vec<Val> = first.into_iter().collect();
+        let mut redirects = vec![];
+        let mut list = env.nil();
+        let op = loop {
+            if !words.is_empty() || !redirects.is_empty() {
+                if let Some(';') = self.peek()? {
+                    let _ = self.next();
+                    break CommandOperator::Seq;
+                }
+                if self.newline_sep {
+                    self.skip_line_continuation();
+                    if let Some(';') = self.peek()? {
+                        self.skip_if(|c|c != '\n');
+                    }
+                    if let Some('\n') = self.peek()? {
                         let _ = self.next();
-                        self.skip_brank();
-                        self.parse(env)
+                        break CommandOperator::Seq;
+                    }
+                }
+            }
+            self.skip_brank();
+            if let Some(redirect) = self.parse_redirect(env, None)? {
+                redirects.push(redirect);
+                continue;
+            }
+            self.standalone = false;
+            match self.parse_word(env)? {
+                Some(word) => {
+                    if word.is_num() && matches!(self.peek()?, Some('<'|'>')) {
+                        if let Some(redirect) = self.parse_redirect(env, Some(word.clone()))? {
+                            redirects.push(redirect);
+                            continue;
+                        }
+                    }
+                    let Some(word) = self.parse_infix(env, word)? else {
+                        return self.syntax_err();
+                    };
+                    if words.is_empty() {
+                        standalone = self.standalone;
+                    }
+                    words.push(word);
+                }
+                _ => {
+                    match self.peek()? {
+                        Some('|') => {
+                            let _ = self.next();
+                            if let Some('|') = self.peek()? {
+                                let _ = self.next();
+                                break CommandOperator::Or;
+                            }
+                            break CommandOperator::Pipe;
+                        }
+                        Some('&') => {
+                            let _ = self.next();
+                            if let Some('&') = self.peek()? {
+                                let _ = self.next();
+                                break CommandOperator::And;
+                            }
+                            self.skip_brank();
+                            self.standalone = false;
+                            let Some(word) = self.parse_word(env)? else {
+                                break CommandOperator::Background(None);
+                            };
+                            let Some(word) = self.parse_infix(env, word)? else {
+                                return self.syntax_err();
+                            };
+                            self.skip_brank();
+                            if matches!(self.peek()?, Some(')')|None) {
+                                list = word;
+                                break CommandOperator::End;
+                            }
+                            break CommandOperator::Background(Some(word));
+                        }
+                        _ => break CommandOperator::End,
                     }
-                    _ => Ok(Some(env.nil()))
                 }
             }
+        };
+        if standalone && words.len() == 1 && list.is_nil() {
+            list = words.pop().unwrap();
         }
+        while let Some(word) = words.pop() {
+            list = cons(word, list);
+        }
+        if redirects.is_empty() {
+            return Ok(Some((list, op)));
+        }
+        let mut specs = env.nil();
+        while let Some(redirect) = redirects.pop() {
+            specs = cons(redirect, specs);
+        }
+        Ok(Some((cons(env.sym.redirect.clone(), cons(specs, cons(list, env.nil()))), op)))
+    }
+    fn parse_redirect(&mut self, env: &Env, fd: Option<Val>) -> Parsed<Val> {
+        if self.at_proc_sub()? {
+            return Ok(None);
+        }
+        let (op, default_fd) = match self.peek()? {
+            Some('>') => {
+                let _ = self.next();
+                match self.peek()? {
+                    Some('>') => { let _ = self.next(); (">>", 1) }
+                    Some('&') => { let _ = self.next(); (">&", 1) }
+                    _ => (">", 1)
+                }
+            }
+            Some('<') => {
+                let _ = self.next();
+                match self.peek()? {
+                    Some('<') => {
+                        let _ = self.next();
+                        if self.peek()? == Some('<') {
+                            let _ = self.next();
+                            ("<<<", 0)
+                        } else {
+                            let Some(body) = self.parse_heredoc(env)? else {
+                                return self.syntax_err();
+                            };
+                            let fd = fd.unwrap_or_else(|| 0isize.into());
+                            return Ok(Some(cons(fd, cons("<<".intern(), cons(body, env.nil())))));
+                        }
+                    }
+                    Some('&') => { let _ = self.next(); ("<&", 0) }
+                    Some('>') => { let _ = self.next(); ("<>", 0) }
+                    _ => ("<", 0)
+                }
+            }
+            _ => return Ok(None)
+        };
+        self.skip_if(|c|c == ' ' || c == '\t');
+        let Some(target) = self.parse_word(env)? else {
+            return self.syntax_err();
+        };
+        let fd = fd.unwrap_or_else(|| (default_fd as isize).into());
+        Ok(Some(cons(fd, cons(op.intern(), cons(target, env.nil())))))
     }
     fn parse(&mut self, env: &Env) -> Parsed<Val> {
         let c = match self.peek()? {
@@ -2206,15 +3539,27 @@ impl<'a, R: std::io::Read> CharsAPI for PeekableReader<'a, R> {
             }
             '(' => {
                 let _ = self.next();
-                let result = self.parse_list(env);
-                self.skip_brank();
-                match self.peek()? {
-                    Some(c) if c == ')' => {
-                        let _ = self.next();
-                        result
-                    }
-                    _ => self.syntax_err(),
-                }
+                self.parse_block(env, ')', false)
+            }
+            '<'|'>' if self.at_proc_sub()? => {
+                let _ = self.next();
+                let _ = self.next();
+                let Some(code) = self.parse_block(env, ')', false)? else {
+                    return Ok(None);
+                };
+                Ok(Some(cons(env.sym.proc_sub.clone(),
+                    cons(c.to_string().intern(), cons(code, env.nil())))))
+            }
+            '{' => {
+                let _ = self.next();
+                let block = match self.parse_block(env, '}', true)? {
+                    Some(block) if block.is_cell() && block.car() == &env.sym.progn => block,
+                    Some(block) if block.is_nil() => cons(env.sym.progn.clone(), block),
+                    Some(block) => cons(env.sym.progn.clone(), cons(block, env.nil())),
+                    _ => return Ok(None)
+                };
+                self.standalone = true;
+                Ok(Some(block))
             }
             '`' => {
                 let _ = self.next();
@@ -2258,12 +3603,12 @@ impl<'a, R: std::io::Read> CharsAPI for PeekableReader<'a, R> {
             }
             '@' => {
                 let _ = self.next();
-                match self.parse(env)? {
+                match self.parse_word(env)? {
                     Some(val) => Ok(Some(cons(env.sym.mval.clone(), cons(val, env.nil())))),
                     _ => Ok(Some("@".intern()))
                 }
             }
-            ')'|'|'|'&'|'{'|'}'|'>'|'<'|' '|'\t'|'\n'|'\r' => {
+            ')'|'|'|'&'|'}'|'>'|'<'|' '|'\t'|'\n'|'\r' => {
                 Ok(None)
             }
             _ => {
@@ -2687,86 +4032,892 @@ fn spawn(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
     if !ast.is_cell() {
         return Err(env.argument_err("spawn", 0, "1"));
     }
-    unsafe {
-        let pid = fork();
-        if pid == -1 {
-            return Err(env.other_err(env.sym.syscall_err.clone(),
-                        "spawn: failed to fork".to_string()));
-        } else if pid == 0 {
-            let old_stack_len = env.arg_stack.len();
-            match env.eval(mode.for_special_form(), ast.car()) {
-                Ok(true) => exit(0),
-                Ok(false) => {
-                    env.arg_stack.truncate(old_stack_len + 1);
-                    let n = if old_stack_len == env.arg_stack.len() {
-                        ONE
-                    } else {
-                        env.arg_stack.pop().unwrap()
-                    };
-                    if n.is_num() {
-                        exit(isize::try_from(n).unwrap() as i32);
-                    } else {
-                        exit(1);
-                    }
-                }
-                _ => exit(1)
+    env.reap_detached();
+    let job_control = env.job_control;
+    let pid = env.fork_eval("spawn", mode, ast.car(), |_| {
+        if job_control {
+            unsafe {
+                libc::setpgid(0, 0);
             }
-        } else {
-            env.push((pid as isize).into());
+        }
+    })?;
+    if job_control {
+        unsafe {
+            libc::setpgid(pid, pid);
         }
     }
+    let _ = env.add_job(pid, pid, ast.car().to_string(), JobState::Running);
+    env.push(Proc::new(pid, vec![]).into());
     Ok(true)
 }
-fn wait_pid(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+fn parallel_each(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
     let old_stack_len = env.eval_args(ast)?;
-    if env.arg_stack.len() - old_stack_len != 1 {
-        return Err(env.argument_err("wait-pid", env.arg_stack.len() - old_stack_len, "1"));
-    }
+    let arg_n = env.arg_stack.len() - old_stack_len;
+    if !(3..=4).contains(&arg_n) {
+        return Err(env.argument_err("parallel-each", arg_n, "3 or 4"));
+    }
+    let mut args: Vec<Val> = env.arg_stack.drain(old_stack_len..).collect();
+    let fail_fast = match args.pop_if(|_| arg_n == 4) {
+        Some(v) if v.to_str().is_ok_and(|name| name == "fail-fast") => true,
+        Some(v) => return Err(env.other_err(env.sym.arg_err.clone(),
+                    format!("parallel-each: {}: unknown option", v))),
+        None => false,
+    };
+    let max = match isize::try_from(args.pop().unwrap()) {
+        Ok(n) if n > 0 => n as usize,
+        Ok(n) => return Err(env.other_err(env.sym.arg_err.clone(),
+                    format!("parallel-each: {}: concurrency must be positive", n))),
+        Err(v) => return Err(env.type_err_conv("parallel-each", &v)),
+    };
+    let mut inputs = args.pop().unwrap();
+    let func = args.pop().unwrap();
 
-    let pid: isize = env.arg_stack.pop().unwrap().try_into()
-        .or_else(|v|Err(env.type_err_conv("wait", &v)))?;
-    let mut status: libc::c_int = 0;
-    unsafe {
-        let ret = waitpid(pid as pid_t, &mut status as *mut _, 0);
-        if ret == -1 {
-            return Err(env.other_err(env.sym.syscall_err.clone(),
-            format!("wait-pid: failed to wait {}", pid)));
+    let job_control = env.job_control;
+    let mut results: Vec<Option<i32>> = vec![];
+    let mut running: StdHashMap<pid_t, usize> = StdHashMap::new();
+    let mut failed = false;
+    loop {
+        while !failed && running.len() < max && inputs.is_cell() {
+            let quoted = |v: &Val| cons(env.sym.quote.clone(), cons(v.clone(), env.nil()));
+            let call = cons(quoted(&func), cons(quoted(inputs.car()), env.nil()));
+            let pid = match env.fork_eval("parallel-each", Mode::Single, &call, |_| {
+                if job_control {
+                    unsafe {
+                        libc::setpgid(0, 0);
+                    }
+                }
+            }) {
+                Ok(pid) => pid,
+                Err(e) => {
+                    for &pid in running.keys() {
+                        unsafe {
+                            libc::kill(if job_control { -pid } else { pid }, libc::SIGKILL);
+                        }
+                    }
+                    let pids: Vec<pid_t> = running.keys().copied().collect();
+                    env.wait_children(&pids);
+                    return Err(e);
+                }
+            };
+            if job_control {
+                unsafe {
+                    libc::setpgid(pid, pid);
+                }
+            }
+            let _ = running.insert(pid, results.len());
+            results.push(None);
+            inputs = inputs.cdr().clone();
         }
-        if WIFEXITED(status) {
-            let code = WEXITSTATUS(status);
-            env.push((code as isize).into());
-            Ok(code == 0)
-        } else {
-            Err(env.other_err(env.sym.syscall_err.clone(),
-            "wait-pid: failed to get error code".to_string()))
+        if running.is_empty() {
+            break;
         }
-    }
-}
-
-fn quote(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
-    env.push(ast.car().clone());
-    Ok(true)
-}
-fn back_quote_internal(env: &mut Env, ast: &Val) -> Result<bool, Exception> {
-    if ast.is_cell() {
-        if ast.car() == &env.sym.unquote {
-            let _ = env.eval(Mode::None, ast.cdr().car())?;
-            Ok(true)
-        } else {
-            let old_stack_len = env.arg_stack.len();
-            let mut unquoted = false;
-            for node in ast {
-                if back_quote_internal(env, node)? {
-                    unquoted = true;
+        let pids: Vec<pid_t> = running.keys().copied().collect();
+        let Some((pid, status)) = env.reap(Some(&pids), false)? else {
+            continue;
+        };
+        let code = exit_status(status);
+        results[running.remove(&pid).unwrap()] = Some(code);
+        if fail_fast && code != 0 && !failed {
+            failed = true;
+            for &pid in running.keys() {
+                unsafe {
+                    libc::kill(if job_control { -pid } else { pid }, libc::SIGTERM);
                 }
             }
-            if unquoted {
-                env.stack_to_list(Mode::None, old_stack_len);
-            } else {
-                env.arg_stack.truncate(old_stack_len);
-                env.push(ast.clone());
-            }
-            Ok(unquoted)
+        }
+    }
+
+    while inputs.is_cell() {
+        results.push(None);
+        inputs = inputs.cdr().clone();
+    }
+    let mut ok = !failed;
+    for result in &results {
+        ok &= *result == Some(0);
+        env.push(result.map_or_else(|| env.nil(), |code| (code as isize).into()));
+    }
+    env.stack_to_list(mode, old_stack_len);
+    Ok(ok)
+}
+const TIMEOUT_KILL_AFTER: std::time::Duration = std::time::Duration::from_secs(1);
+fn timeout(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let arg_n = ast.into_iter().count();
+    let (Some(secs), Some(code)) = (ast.next(), ast.next()) else {
+        return Err(env.argument_err("timeout", arg_n, "2 or 3"));
+    };
+    if arg_n > 3 {
+        return Err(env.argument_err("timeout", arg_n, "2 or 3"));
+    }
+    env.eval(Mode::Single, secs)?;
+    let secs = env.arg_stack.pop().unwrap();
+    let secs = f64::try_from(secs).map_err(|v| env.type_err_conv("timeout", &v))?;
+    let sig = match ast.next() {
+        Some(v) => {
+            env.eval(Mode::Single, v)?;
+            let v = env.arg_stack.pop().unwrap();
+            signal_arg(env, "timeout", &v)?
+        }
+        None => libc::SIGTERM,
+    };
+
+    let Ok(deadline) = std::time::Duration::try_from_secs_f64(secs.max(0.0)) else {
+        return Err(env.other_err(env.sym.arg_err.clone(),
+            format!("timeout: {}: invalid duration", secs)));
+    };
+
+    let job_control = env.job_control;
+    let pid = env.fork_eval("timeout", mode, code, |_| {
+        if job_control {
+            unsafe {
+                libc::setpgid(0, 0);
+            }
+        }
+    })?;
+    if job_control {
+        unsafe {
+            libc::setpgid(pid, pid);
+            libc::tcsetpgrp(0, pid);
+        }
+    }
+    let target = if job_control { -pid } else { pid };
+    let start = std::time::Instant::now();
+    let mut killed: Option<std::time::Instant> = None;
+    let mut status: libc::c_int = 0;
+    let mut error = None;
+    loop {
+        match unsafe { waitpid(pid, &mut status, libc::WNOHANG) } {
+            0 => {}
+            -1 if io::Error::last_os_error().kind() == ErrorKind::Interrupted => {}
+            -1 => {
+                error = Some(io::Error::last_os_error());
+                break;
+            }
+            _ => break,
+        }
+        match killed {
+            None if start.elapsed() >= deadline => {
+                unsafe {
+                    libc::kill(target, sig);
+                }
+                killed = Some(std::time::Instant::now());
+            }
+            Some(at) if at.elapsed() >= TIMEOUT_KILL_AFTER => {
+                unsafe {
+                    libc::kill(target, libc::SIGKILL);
+                }
+                while unsafe { waitpid(pid, &mut status, 0) } == -1
+                    && io::Error::last_os_error().kind() == ErrorKind::Interrupted {}
+                break;
+            }
+            _ => {}
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    if job_control {
+        unsafe {
+            libc::tcsetpgrp(0, env.shell_pgid);
+        }
+    }
+    if let Some(e) = error {
+        return Err(env.other_err(env.sym.syscall_err.clone(),
+            format!("timeout: failed to wait {}: detail={}", pid, e)));
+    }
+    if killed.is_some() {
+        return Err(env.other_err(env.sym.timeout_err.clone(),
+            format!("timeout: timed out after {}s", secs)));
+    }
+    let code = exit_status(status);
+    env.push((code as isize).into());
+    Ok(code == 0)
+}
+// the resource argument of getrlimit/setrlimit is an unsigned enum only on glibc
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type RlimitResource = libc::c_int;
+const RLIMITS: &[(&str, RlimitResource)] = &[
+    ("cpu", libc::RLIMIT_CPU), ("fsize", libc::RLIMIT_FSIZE), ("data", libc::RLIMIT_DATA),
+    ("stack", libc::RLIMIT_STACK), ("core", libc::RLIMIT_CORE), ("rss", libc::RLIMIT_RSS),
+    ("nproc", libc::RLIMIT_NPROC), ("nofile", libc::RLIMIT_NOFILE), ("memlock", libc::RLIMIT_MEMLOCK),
+    ("as", libc::RLIMIT_AS),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    ("locks", libc::RLIMIT_LOCKS),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    ("sigpending", libc::RLIMIT_SIGPENDING),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    ("msgqueue", libc::RLIMIT_MSGQUEUE),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    ("nice", libc::RLIMIT_NICE),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    ("rtprio", libc::RLIMIT_RTPRIO),
+];
+fn rlimit_resource(env: &mut Env, name: &str, v: &Val) -> Result<(String, RlimitResource), Exception> {
+    let spec = v.to_str()
+        .map_err(|_| env.type_err_to_str(name, v))?.into_owned();
+    match RLIMITS.iter().find(|(n, _)| *n == spec) {
+        Some(&(_, resource)) => Ok((spec, resource)),
+        None => Err(env.other_err(env.sym.arg_err.clone(),
+            format!("{}: {}: unknown resource", name, spec))),
+    }
+}
+fn rlimit_value(env: &mut Env, name: &str, v: &Val) -> Result<libc::rlim_t, Exception> {
+    if v.is_num() {
+        let n = isize::try_from(v.clone()).unwrap();
+        return libc::rlim_t::try_from(n).map_err(|_| env.type_err_conv(name, v));
+    }
+    let spec = v.to_str()
+        .map_err(|_| env.type_err_to_str(name, v))?.into_owned();
+    if spec == "unlimited" {
+        return Ok(libc::RLIM_INFINITY);
+    }
+    let (digits, unit) = match spec.char_indices().last() {
+        Some((i, 'k'|'K')) => (&spec[..i], 1 << 10),
+        Some((i, 'm'|'M')) => (&spec[..i], 1 << 20),
+        Some((i, 'g'|'G')) => (&spec[..i], 1 << 30),
+        _ => (&spec[..], 1),
+    };
+    digits.parse::<libc::rlim_t>().ok().and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| env.type_err_conv(name, v))
+}
+fn rlimit_to_val(n: libc::rlim_t) -> Val {
+    if n == libc::RLIM_INFINITY {
+        "unlimited".intern()
+    } else {
+        (n as isize).into()
+    }
+}
+fn get_rlimit(env: &mut Env, name: &str, resource: RlimitResource) -> Result<libc::rlimit, Exception> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(resource, &mut limit) } == -1 {
+        return Err(env.other_err(env.sym.syscall_err.clone(),
+            format!("{}: failed to get the limit: detail={}", name, io::Error::last_os_error())));
+    }
+    Ok(limit)
+}
+// as with ulimit, a single value sets only the soft limit unless -H is given
+fn limit(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let mut args: Vec<Val> = env.arg_stack.drain(old_stack_len..).collect();
+    let arg_n = args.len();
+    let hard = args.first().is_some_and(|v| v.to_str().is_ok_and(|option| option == "-H"));
+    if hard {
+        let _ = args.remove(0);
+    }
+    if !(1..=3).contains(&args.len()) {
+        return Err(env.argument_err("limit", arg_n, if hard { "2 to 4" } else { "1 to 3" }));
+    }
+    let (spec, resource) = rlimit_resource(env, "limit", &args[0])?;
+    if args.len() == 3 && !hard {
+        return Err(env.other_err(env.sym.arg_err.clone(),
+            format!("limit: {}: changing the hard limit needs -H; use with-limits to limit a child", spec)));
+    }
+    let mut limit = get_rlimit(env, "limit", resource)?;
+    if args.len() > 1 {
+        limit.rlim_cur = rlimit_value(env, "limit", &args[1])?;
+        limit.rlim_max = match args.get(2) {
+            Some(v) => rlimit_value(env, "limit", v)?,
+            None if hard => limit.rlim_cur,
+            None => limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } == -1 {
+            return Err(env.other_err(env.sym.syscall_err.clone(),
+                format!("limit: {}: detail={}", spec, io::Error::last_os_error())));
+        }
+    }
+    env.push(rlimit_to_val(limit.rlim_cur));
+    env.push(rlimit_to_val(limit.rlim_max));
+    env.stack_to_list(mode, old_stack_len);
+    Ok(true)
+}
+fn with_limits(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    if !ast.is_cell() || !ast.cdr().is_cell() || ast.cdr().cdr().is_cell() {
+        return Err(env.argument_err("with-limits", ast.into_iter().count(), "2"));
+    }
+    let old_stack_len = env.eval_args(ast.car())?;
+    if !(env.arg_stack.len() - old_stack_len).is_multiple_of(2) {
+        env.arg_stack.truncate(old_stack_len);
+        return Err(env.other_err(env.sym.arg_err.clone(),
+            "with-limits: limits must be pairs of a resource and a value".to_string()));
+    }
+    let args: Vec<Val> = env.arg_stack.drain(old_stack_len..).collect();
+    let mut limits = vec![];
+    for pair in args.chunks(2) {
+        let (spec, resource) = rlimit_resource(env, "with-limits", &pair[0])?;
+        let (soft, hard) = if pair[1].is_cell() {
+            let values: Vec<&Val> = pair[1].into_iter().collect();
+            let &[soft, hard] = &values[..] else {
+                return Err(env.other_err(env.sym.arg_err.clone(),
+                    format!("with-limits: {}: expected a value or (soft hard)", spec)));
+            };
+            (rlimit_value(env, "with-limits", soft)?, rlimit_value(env, "with-limits", hard)?)
+        } else {
+            let n = rlimit_value(env, "with-limits", &pair[1])?;
+            (n, n)
+        };
+        if soft > hard {
+            return Err(env.other_err(env.sym.arg_err.clone(),
+                format!("with-limits: {}: soft limit exceeds hard limit", spec)));
+        }
+        limits.push((spec, resource, libc::rlimit { rlim_cur: soft, rlim_max: hard }));
+    }
+
+    let pid = env.fork_eval("with-limits", mode, ast.cdr().car(), |_| {
+        for (spec, resource, limit) in &limits {
+            if unsafe { libc::setrlimit(*resource, limit) } == -1 {
+                eprintln!("with-limits: {}: detail={}", spec, io::Error::last_os_error());
+                exit(1);
+            }
+        }
+    })?;
+    let (_, status) = env.reap(Some(&[pid]), false)?.unwrap();
+    let code = exit_status(status);
+    env.push((code as isize).into());
+    Ok(code == 0)
+}
+fn coproc(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    if !ast.is_cell() || ast.cdr().is_cell() {
+        return Err(env.argument_err("coproc", ast.into_iter().count(), "1"));
+    }
+    let pipes = std::io::pipe().and_then(|input| std::io::pipe().map(|output| (input, output)));
+    let ((child_in, writer), (reader, child_out)) = pipes.map_err(|e| env.other_err(
+        env.sym.syscall_err.clone(), format!("coproc: failed to create pipe: detail={}", e)))?;
+    let (writer, reader) = (Val::from(writer), Val::from(reader));
+    let (child_in, child_out) = (Val::from(child_in), Val::from(child_out));
+    env.coproc_fds.retain(|&(fd, id)| fd_identity(fd) == Some(id));
+    for fd in [writer.raw_fd().unwrap(), reader.raw_fd().unwrap()] {
+        env.coproc_fds.extend(fd_identity(fd).map(|id| (fd, id)));
+    }
+    env.reap_detached();
+    let pid = env.fork_eval("coproc", mode, ast.car(), |env| {
+        env.sym.stdin.var().val = child_in.clone();
+        env.sym.stdout.var().val = child_out.clone();
+    })?;
+    let _ = env.add_job(pid, pid, ast.car().to_string(), JobState::Running);
+    env.push(Proc::new(pid, vec![writer.clone(), reader.clone()]).into());
+    env.push(writer);
+    env.push(reader);
+    env.stack_to_list(mode, env.arg_stack.len() - 3);
+    Ok(true)
+}
+fn pipeline(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    if !ast.is_cell() {
+        return Err(env.argument_err("|", 0, "1 or more"));
+    }
+    let job_control = env.job_control;
+    let mut pgid = 0;
+    let mut pids = Vec::new();
+    let mut reader: Option<Val> = None;
+    let mut stages = ast;
+    while stages.is_cell() && (job_control || stages.cdr().is_cell()) {
+        let pipe = if stages.cdr().is_cell() {
+            match std::io::pipe() {
+                Ok((r, w)) => Some((Val::from(r), Val::from(w))),
+                Err(e) => {
+                    drop(reader.take());
+                    env.wait_children(&pids);
+                    return Err(env.other_err(env.sym.syscall_err.clone(),
+                        format!("|: failed to create pipe: detail={}", e)));
+                }
+            }
+        } else {
+            None
+        };
+        let input = reader.take();
+        let r_fd = pipe.as_ref().map(|(r, _)| r.piper().as_raw_fd());
+        let w = pipe.as_ref().map(|(_, w)| w.clone());
+        let forked = env.fork_eval("|", Mode::Single, stages.car(), |env| {
+            unsafe {
+                if job_control {
+                    libc::setpgid(0, pgid);
+                }
+                if let Some(fd) = r_fd {
+                    libc::close(fd);
+                }
+            }
+            if let Some(input) = &input {
+                env.sym.stdin.var().val = input.clone();
+            }
+            if let Some(w) = w {
+                env.sym.stdout.var().val = w;
+            }
+        });
+        drop(input);
+        match forked {
+            Ok(pid) => {
+                if job_control {
+                    unsafe {
+                        libc::setpgid(pid, pgid);
+                    }
+                    if pgid == 0 {
+                        pgid = pid;
+                    }
+                }
+                pids.push(pid);
+            }
+            Err(e) => {
+                env.wait_children(&pids);
+                return Err(e);
+            }
+        }
+        reader = pipe.map(|(r, _)| r);
+        stages = stages.cdr();
+    }
+    if job_control {
+        let cmd = ast.into_iter().map(|stage| stage.to_string()).collect::<Vec<_>>().join(" | ");
+        return env.wait_foreground(pgid, *pids.last().unwrap(), cmd, false);
+    }
+
+    let result = match reader {
+        Some(mut r) => {
+            std::mem::swap(&mut env.sym.stdin.var().val, &mut r);
+            let result = env.eval(mode.for_special_form(), stages.car());
+            std::mem::swap(&mut env.sym.stdin.var().val, &mut r);
+            result
+        }
+        None => env.eval(mode.for_special_form(), stages.car()),
+    };
+    env.wait_children(&pids);
+    result
+}
+fn redirect(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let specs = ast.next().ok_or_else(|| env.argument_err("redirect", 0, "1 or more"))?;
+    let std_in = env.sym.stdin.var().val.clone();
+    let std_out = env.sym.stdout.var().val.clone();
+    let std_err = env.sym.stderr.var().val.clone();
+    let old_fds_len = env.fds.len();
+    let mut proc_subs = vec![];
+
+    let mut result = Ok(true);
+    for spec in specs {
+        if let Err(e) = env.redirect_fd(spec, &mut proc_subs) {
+            result = Err(e);
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = progn(env, mode, ast);
+    }
+
+    env.sym.stdin.var().val = std_in;
+    env.sym.stdout.var().val = std_out;
+    env.sym.stderr.var().val = std_err;
+    env.fds.truncate(old_fds_len);
+    drop(proc_subs);
+    result
+}
+fn proc_sub(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let (Some(dir), Some(code), None) = (ast.next(), ast.next(), ast.next()) else {
+        return Err(env.argument_err("proc-sub", ast.into_iter().count(), "2"));
+    };
+    let input = match &*dir.to_str().unwrap_or_default() {
+        "<" => true,
+        ">" => false,
+        _ => return Err(env.other_err(env.sym.arg_err.clone(),
+                format!("proc-sub: {}: unknown direction", dir))),
+    };
+    let (r, w) = std::io::pipe().map_err(|e| env.other_err(env.sym.syscall_err.clone(),
+        format!("proc-sub: failed to create pipe: detail={}", e)))?;
+    let (ours, theirs): (Val, Val) = if input { (r.into(), w.into()) } else { (w.into(), r.into()) };
+    let our_fd = ours.raw_fd().unwrap();
+    let child_end = theirs.clone();
+    let pid = env.fork_eval("proc-sub", mode, code, move |env| {
+        unsafe {
+            libc::close(our_fd);
+        }
+        if input {
+            env.sym.stdout.var().val = child_end;
+        } else {
+            env.sym.stdin.var().val = child_end;
+        }
+    })?;
+    drop(theirs);
+    env.push(ProcSub { pid, pipe: ours }.into());
+    Ok(true)
+}
+fn capture(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let arg_n = ast.into_iter().count();
+    if arg_n == 0 || arg_n > 2 {
+        return Err(env.argument_err("capture", arg_n, "1 or 2"));
+    }
+    let code = ast.car().clone();
+    let split = if arg_n == 2 {
+        env.eval(Mode::Single, ast.cdr().car())?;
+        let v = env.arg_stack.pop().unwrap();
+        let name = v.to_str()
+            .map_err(|_| env.type_err_to_str("capture", &v))?.into_owned();
+        if name != "lines" && name != "ifs" {
+            return Err(env.other_err(env.sym.arg_err.clone(),
+                format!("capture: {}: unknown split mode", name)));
+        }
+        Some(name)
+    } else {
+        None
+    };
+
+    let (mut r, w) = std::io::pipe().map_err(|e| env.other_err(env.sym.syscall_err.clone(),
+        format!("capture: failed to create pipe: detail={}", e)))?;
+    let reader = std::thread::spawn(move || {
+        let mut out = Vec::new();
+        let _ = r.read_to_end(&mut out);
+        out
+    });
+
+    let std_out = std::mem::replace(&mut env.sym.stdout.var().val, w.into());
+    let old_stack_len = env.arg_stack.len();
+    let result = env.eval(Mode::Multi, &code);
+    let _ = env.sym.stdout.var().val.flush();
+    env.sym.stdout.var().val = std_out;
+    let out = reader.join().unwrap_or_default();
+    let status = result?;
+    env.arg_stack.truncate(old_stack_len);
+
+    let out = String::from_utf8_lossy(&out);
+    match split.as_deref() {
+        Some("lines") => {
+            for line in out.lines() {
+                env.push(line.to_string().to_str());
+            }
+            env.stack_to_list(mode, old_stack_len);
+        }
+        Some(_) => {
+            let tmp = env.sym.ifs.var().val.clone();
+            let ifs = tmp.to_str().map(|s| s.into_owned()).unwrap_or_default();
+            for field in out.split(|c| ifs.contains(c) || c == '\n').filter(|f| !f.is_empty()) {
+                env.push(field.to_string().to_str());
+            }
+            env.stack_to_list(mode, old_stack_len);
+        }
+        None => env.push(out.trim_end_matches('\n').to_string().to_str()),
+    }
+    Ok(status)
+}
+fn argv0(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let name = ast.next().ok_or_else(|| env.argument_err("argv0", 0, "1 or more"))?;
+    env.eval(Mode::Single, name)?;
+    let name = env.arg_stack.pop().unwrap();
+    let outer = env.argv0.replace(name);
+    let result = progn(env, mode, ast);
+    env.argv0 = outer;
+    result
+}
+fn run(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let form = match ast.next() {
+        Some(form) if form.is_cell() => form,
+        _ => return Err(env.argument_err("run", 0, "1 or more")),
+    };
+    let (mut capture_out, mut capture_err) = (false, false);
+    for v in ast {
+        env.eval(Mode::Single, v)?;
+        let v = env.arg_stack.pop().unwrap();
+        let name = v.to_str()
+            .map_err(|_| env.type_err_to_str("run", &v))?.into_owned();
+        match &*name {
+            "stdout" => capture_out = true,
+            "stderr" => capture_err = true,
+            _ => return Err(env.other_err(env.sym.arg_err.clone(),
+                format!("run: {}: unknown option", name))),
+        }
+    }
+
+    env.eval(Mode::Single, form.car())?;
+    let v = env.arg_stack.pop().unwrap();
+    let cmd = v.to_path()
+        .map_err(|_| env.type_err_to_str("run", &v))?.into_owned();
+    let (mut command, _proc_subs) = env.build_command(&cmd, form.cdr())?;
+    if capture_out {
+        command.stdout(Stdio::piped());
+    }
+    if capture_err {
+        command.stderr(Stdio::piped());
+    }
+
+    let start = std::time::Instant::now();
+    let mut child = command.spawn().map_err(|e| env.other_err(env.sym.syscall_err.clone(),
+        format!("run: {}: detail={}", cmd.display(), e)))?;
+    let read_all = |r: Option<Box<dyn Read + Send>>| r.map(|mut r| std::thread::spawn(move || {
+        let mut out = Vec::new();
+        let _ = r.read_to_end(&mut out);
+        out
+    }));
+    let out = read_all(child.stdout.take().map(|r| Box::new(r) as Box<dyn Read + Send>));
+    let err = read_all(child.stderr.take().map(|r| Box::new(r) as Box<dyn Read + Send>));
+
+    let pid = child.id() as pid_t;
+    let mut status: libc::c_int = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let ret = loop {
+        let ret = unsafe { libc::wait4(pid, &mut status, 0, &mut usage) };
+        if ret != -1 || io::Error::last_os_error().kind() != ErrorKind::Interrupted {
+            break ret;
+        }
+    };
+    let real = start.elapsed().as_secs_f64();
+    if ret == -1 {
+        return Err(env.other_err(env.sym.syscall_err.clone(),
+            format!("run: failed to wait {}: detail={}", pid, io::Error::last_os_error())));
+    }
+
+    let result = Val::new_dict();
+    let code = exit_status(status);
+    result.dict().insert("code".into(), (code as isize).into());
+    let signal = if libc::WIFSIGNALED(status) {
+        signal_name(libc::WTERMSIG(status)).map_or_else(
+            || (libc::WTERMSIG(status) as isize).into(), |name| name.intern())
+    } else {
+        env.nil()
+    };
+    result.dict().insert("signal".into(), signal);
+    let core = if libc::WIFSIGNALED(status) && libc::WCOREDUMP(status) {
+        env.sym.t.clone()
+    } else {
+        env.nil()
+    };
+    result.dict().insert("core".into(), core);
+    for (key, reader) in [("stdout", out), ("stderr", err)] {
+        if let Some(reader) = reader {
+            let bytes = reader.join().unwrap_or_default();
+            result.dict().insert(key.into(), String::from_utf8_lossy(&bytes).into_owned().to_str());
+        }
+    }
+    let secs = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1e6;
+    result.dict().insert("real".into(), real.into());
+    result.dict().insert("user".into(), secs(usage.ru_utime).into());
+    result.dict().insert("sys".into(), secs(usage.ru_stime).into());
+    env.push(result);
+    Ok(code == 0)
+}
+fn wait_pid(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let arg_n = env.arg_stack.len() - old_stack_len;
+    if arg_n == 0 {
+        return Err(env.argument_err("wait-pid", arg_n, "1 or more"));
+    }
+    env.arg_stack[old_stack_len..].reverse();
+    let target = env.arg_stack.pop().unwrap();
+    let (mut nohang, mut detail) = (false, false);
+    for _ in 1..arg_n {
+        let v = env.arg_stack.pop().unwrap();
+        let name = v.to_str()
+            .map_err(|_| env.type_err_to_str("wait-pid", &v))?.into_owned();
+        match &*name {
+            "nohang" => nohang = true,
+            "detail" => detail = true,
+            _ => return Err(env.other_err(env.sym.arg_err.clone(),
+                    format!("wait-pid: {}: unknown option", name))),
+        }
+    }
+
+    let mut targets = vec![];
+    let single = !target.is_nil() && !target.is_cell();
+    if single {
+        targets.push(target.clone());
+    } else {
+        targets.extend(target.into_iter().cloned());
+    }
+    let pids = targets.iter().map(|v| isize::try_from(v.clone()).map(|n| n as pid_t))
+        .collect::<Result<Vec<pid_t>, Val>>()
+        .map_err(|v| env.type_err_conv("wait-pid", &v))?;
+
+    let cached = targets.iter().find(|v| v.is_proc() && v.proc().status.is_some());
+    let (pid, status) = match cached {
+        Some(v) => (v.proc().pid, v.proc().status.unwrap()),
+        None => {
+            let pids = if target.is_nil() { None } else { Some(&pids[..]) };
+            let Some((pid, status)) = env.reap(pids, nohang)? else {
+                env.push(env.nil());
+                return Ok(false);
+            };
+            (pid, status)
+        }
+    };
+    let handle = targets.iter().find(|v| v.is_proc() && v.proc().pid == pid);
+    if let Some(v) = handle {
+        v.proc().status = Some(status);
+    }
+    env.jobs.retain(|job| job.pid != pid);
+    let code = exit_status(status);
+    if single && !detail {
+        env.push((code as isize).into());
+    } else {
+        env.push(handle.map_or_else(|| (pid as isize).into(), |v| v.clone()));
+        env.push((code as isize).into());
+        env.push(if libc::WIFSIGNALED(status) {
+            signal_name(libc::WTERMSIG(status)).map_or_else(
+                || (libc::WTERMSIG(status) as isize).into(), |name| name.intern())
+        } else {
+            env.nil()
+        });
+        env.stack_to_list(mode, old_stack_len);
+    }
+    Ok(code == 0)
+}
+
+fn jobs(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    if ast.is_cell() {
+        return Err(env.argument_err("jobs", ast.into_iter().count(), "0"));
+    }
+    env.update_jobs();
+    let old_stack_len = env.arg_stack.len();
+    let jobs = std::mem::take(&mut env.jobs);
+    for job in &jobs {
+        let state = match job.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done(0) => "Done".to_string(),
+            JobState::Done(code) => format!("Exit {}", code),
+            JobState::Unknown => "Unknown".to_string(),
+        };
+        let _ = writeln!(env.sym.stdout.var().val, "[{}]  {:<10} {}", job.id, state, job.cmd);
+        env.push((job.id as isize).into());
+    }
+    let _ = env.sym.stdout.var().val.flush();
+    env.jobs = jobs.into_iter().filter(|job| !matches!(job.state, JobState::Done(_) | JobState::Unknown)).collect();
+    env.stack_to_list(mode, old_stack_len);
+    Ok(true)
+}
+fn job_arg(env: &mut Env, name: &str, ast: &Val) -> Result<usize, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let arg_n = env.arg_stack.len() - old_stack_len;
+    if arg_n > 1 {
+        return Err(env.argument_err(name, arg_n, "0 or 1"));
+    }
+    let spec = env.arg_stack.pop_if(|_| arg_n == 1);
+    env.update_jobs();
+    env.find_job(name, spec.as_ref())
+}
+fn fg(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let i = job_arg(env, "fg", ast)?;
+    let (pid, last, cmd) = (env.jobs[i].pid, env.jobs[i].last, env.jobs[i].cmd.clone());
+    match env.jobs[i].state {
+        JobState::Done(code) => {
+            env.jobs.remove(i);
+            env.push((code as isize).into());
+            return Ok(code == 0);
+        }
+        JobState::Unknown => {
+            env.jobs.remove(i);
+            env.push(env.nil());
+            return Ok(false);
+        }
+        _ => {}
+    }
+    env.jobs[i].state = JobState::Running;
+    env.wait_foreground(pid, last, cmd, true)
+}
+fn bg(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let i = job_arg(env, "bg", ast)?;
+    let job = &mut env.jobs[i];
+    if job.state == JobState::Stopped {
+        unsafe {
+            libc::kill(-job.pid, libc::SIGCONT);
+        }
+        job.state = JobState::Running;
+    }
+    let id = job.id;
+    env.push((id as isize).into());
+    Ok(true)
+}
+fn disown(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let i = job_arg(env, "disown", ast)?;
+    let job = env.jobs.remove(i);
+    env.push((job.pid as isize).into());
+    Ok(true)
+}
+fn signal_arg(env: &mut Env, name: &str, v: &Val) -> Result<libc::c_int, Exception> {
+    let spec = v.to_str()
+        .map_err(|_| env.type_err_to_str(name, v))?.into_owned();
+    let sig = spec.strip_prefix('-').unwrap_or(&spec);
+    if sig == "0" {
+        return Ok(0);
+    }
+    signal_number(sig).ok_or_else(|| env.other_err(env.sym.arg_err.clone(),
+        format!("{}: {}: invalid signal specification", name, spec)))
+}
+fn signal_name_(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    if env.arg_stack.len() - old_stack_len != 1 {
+        return Err(env.argument_err("signal-name", env.arg_stack.len() - old_stack_len, "1"));
+    }
+    let v = env.arg_stack.pop().unwrap();
+    let sig = signal_arg(env, "signal-name", &v)?;
+    env.push(signal_name(sig).map_or_else(|| env.nil(), |name| name.intern()));
+    Ok(true)
+}
+fn signal_number_(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    if env.arg_stack.len() - old_stack_len != 1 {
+        return Err(env.argument_err("signal-number", env.arg_stack.len() - old_stack_len, "1"));
+    }
+    let v = env.arg_stack.pop().unwrap();
+    let sig = signal_arg(env, "signal-number", &v)?;
+    env.push((sig as isize).into());
+    Ok(true)
+}
+fn kill(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let arg_n = env.arg_stack.len() - old_stack_len;
+    if arg_n == 0 || arg_n > 2 {
+        return Err(env.argument_err("kill", arg_n, "1 or 2"));
+    }
+    let target = env.arg_stack.pop().unwrap();
+    let sig = match env.arg_stack.pop_if(|_| arg_n == 2) {
+        Some(v) => signal_arg(env, "kill", &v)?,
+        None => libc::SIGTERM,
+    };
+    if target.is_proc() && target.proc().status.is_some() {
+        return Err(env.other_err(env.sym.syscall_err.clone(),
+            format!("kill: {}: process has already been reaped", target.proc().pid)));
+    }
+    let spec = if target.is_proc() {
+        target.proc().pid.to_string()
+    } else {
+        target.to_str()
+            .map_err(|_| env.type_err_to_str("kill", &target))?.into_owned()
+    };
+    let pid = if spec.starts_with('%') {
+        env.update_jobs();
+        let i = env.find_job("kill", Some(&target))?;
+        if env.job_control { -env.jobs[i].pid } else { env.jobs[i].pid }
+    } else {
+        spec.parse::<pid_t>().map_err(|_| env.type_err_conv("kill", &target))?
+    };
+    if unsafe { libc::kill(pid, sig) } == -1 {
+        return Err(env.other_err(env.sym.syscall_err.clone(),
+            format!("kill: {}: detail={}", spec, io::Error::last_os_error())));
+    }
+    env.push(target);
+    Ok(true)
+}
+
+fn quote(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    env.push(ast.car().clone());
+    Ok(true)
+}
+fn back_quote_internal(env: &mut Env, ast: &Val) -> Result<bool, Exception> {
+    if ast.is_cell() {
+        if ast.car() == &env.sym.unquote {
+            let _ = env.eval(Mode::None, ast.cdr().car())?;
+            Ok(true)
+        } else {
+            let old_stack_len = env.arg_stack.len();
+            let mut unquoted = false;
+            for node in ast {
+                if back_quote_internal(env, node)? {
+                    unquoted = true;
+                }
+            }
+            if unquoted {
+                env.stack_to_list(Mode::None, old_stack_len);
+            } else {
+                env.arg_stack.truncate(old_stack_len);
+                env.push(ast.clone());
+            }
+            Ok(unquoted)
         }
     } else {
         env.push(ast.clone());
@@ -2782,7 +4933,62 @@ fn gensym(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
     Ok(true)
 }
 
-fn trap(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+fn trap(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let arg_n = ast.into_iter().count();
+    if arg_n == 0 || arg_n > 2 {
+        return Err(env.argument_err("trap", arg_n, "1 or 2"));
+    }
+    let _ = env.eval(Mode::Single, ast.next().unwrap())?;
+    let name = env.arg_stack.pop().unwrap();
+    let name = name.to_str()
+        .map_err(|_| env.type_err_to_str("trap", &name))?.into_owned();
+    let handler = ast.next();
+
+    let key = match &*name {
+        "error"|"exit" => name,
+        _ => {
+            let Some(sig) = signal_number(&name) else {
+                return Err(env.other_err(env.sym.arg_err.clone(),
+                    format!("trap: {}: invalid signal specification", name)));
+            };
+            let action = match handler {
+                None if env.job_control && JOB_SIGNALS.contains(&sig) => job_signal_action(sig),
+                None => libc::SIG_DFL,
+                Some(h) if h.is_nil() => libc::SIG_IGN,
+                Some(_) => queue_signal as *const () as libc::sighandler_t,
+            };
+            if unsafe { libc::signal(sig, action) } == libc::SIG_ERR {
+                return Err(env.other_err(env.sym.syscall_err.clone(),
+                    format!("trap: {}: failed to set signal handler: detail={}",
+                    name, io::Error::last_os_error())));
+            }
+            signal_name(sig).unwrap().to_string()
+        }
+    };
+    let old = match handler {
+        Some(h) => env.traps.insert(key, h.clone()),
+        None => env.traps.remove(&key),
+    };
+    env.push(old.unwrap_or_else(|| env.nil()));
+    Ok(true)
+}
+
+fn complete(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let arg_n = ast.into_iter().count();
+    if arg_n == 0 || arg_n > 2 {
+        return Err(env.argument_err("complete", arg_n, "1 or 2"));
+    }
+    let _ = env.eval(Mode::Single, ast.next().unwrap())?;
+    let name = env.arg_stack.pop().unwrap();
+    let name = name.to_str()
+        .map_err(|_| env.type_err_to_str("complete", &name))?.into_owned();
+    let old = match ast.next() {
+        Some(h) => env.completers.insert(name, h.clone()),
+        None => env.completers.remove(&name),
+    };
+    env.push(old.unwrap_or_else(|| env.nil()));
     Ok(true)
 }
 
@@ -3316,6 +5522,13 @@ fn is_chars(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
     }
     Ok(env.arg_stack[old_stack_len].is_chars())
 }
+fn is_process(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    if old_stack_len + 1 != env.arg_stack.len() {
+        return Err(env.argument_err("is_process", env.arg_stack.len() - old_stack_len, "1"));
+    }
+    Ok(env.arg_stack[old_stack_len].is_proc())
+}
 fn is_file(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
     let old_stack_len = env.eval_args(ast)?;
     if old_stack_len + 1 != env.arg_stack.len() {
@@ -3933,6 +6146,19 @@ fn pipe(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
         }
     }
 }
+fn close(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    if env.arg_stack.len() - old_stack_len != 1 {
+        return Err(env.argument_err("close", env.arg_stack.len() - old_stack_len, "1"));
+    }
+    let v = env.arg_stack.pop().unwrap();
+    if !(v.is_file() || v.is_piper() || v.is_pipew() || v.is_buf()) {
+        return Err(env.type_err("close", &v, "fd or buffered"));
+    }
+    drop(std::mem::replace(v.fat(), Fat::Nothing));
+    env.push(env.nil());
+    Ok(true)
+}
 fn buf(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
     let old_stack_len = env.eval_args(ast)?;
     if env.arg_stack.len() - old_stack_len != 1 {
@@ -4054,12 +6280,12 @@ fn getenv(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
     let var = env.arg_stack.pop().unwrap();
     let s = var.to_path()
             .or_else(|_|Err(env.type_err_to_str("env-var", &var)))?;
-    let result = match env::var(s.as_os_str()) {
-        Ok(value) => {
-            env.push(value.to_str());
+    let result = match env.var_os(s.as_os_str()) {
+        Some(value) => {
+            env.push(PathBuf::from(value).to_str());
             Ok(true)
         }
-        Err(e) => {
+        None => {
             env.push(env.nil());
             Ok(false)
         }
@@ -4068,29 +6294,277 @@ fn getenv(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
         let new = std::mem::replace(&mut env.set_val, env.sym.swap_done.clone());
         let t = new.to_path()
             .or_else(|_|Err(env.type_err_to_str("env-var", &env.set_val.clone())))?;
-        env::set_var(s.as_os_str(), t.as_os_str());
+        env.set_var(s.as_os_str(), Some(t.as_os_str()));
+    }
+    result
+}
+
+fn cd(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let arg_n = env.arg_stack.len() - old_stack_len;
+    if arg_n > 1 {
+        return Err(env.argument_err("cd", arg_n, "0 or 1"));
+    }
+    let dir = match env.arg_stack.pop_if(|_| arg_n == 1) {
+        Some(v) => v.to_path()
+            .map_err(|_| env.type_err_to_str("cd", &v))?.into_owned(),
+        None => env.var_os("HOME").map(PathBuf::from).ok_or_else(|| env.other_err(
+            env.sym.arg_err.clone(), "cd: HOME not set".to_string()))?,
+    };
+    let dir = if dir == Path::new("-") {
+        env.var_os("OLDPWD").map(PathBuf::from).ok_or_else(|| env.other_err(
+            env.sym.arg_err.clone(), "cd: OLDPWD not set".to_string()))?
+    } else {
+        cdpath_lookup(env, dir)
+    };
+    let new = env.change_dir("cd", &dir)?;
+    env.push(new.to_str());
+    Ok(true)
+}
+fn cdpath_lookup(env: &Env, dir: PathBuf) -> PathBuf {
+    let explicit = matches!(dir.components().next(),
+        None | Some(std::path::Component::RootDir | std::path::Component::CurDir | std::path::Component::ParentDir));
+    if explicit {
+        return dir;
+    }
+    let cdpath = env.var_os("CDPATH").unwrap_or_default();
+    env::split_paths(&cdpath)
+        .map(|base| if base.as_os_str().is_empty() { dir.clone() } else { base.join(&dir) })
+        .find(|candidate| candidate.is_dir())
+        .unwrap_or(dir)
+}
+fn pwd(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let arg_n = env.arg_stack.len() - old_stack_len;
+    if arg_n > 1 {
+        return Err(env.argument_err("pwd", arg_n, "0 or 1"));
+    }
+    let physical = match env.arg_stack.pop_if(|_| arg_n == 1) {
+        Some(v) => match &*v.to_str().map_err(|_| env.type_err_to_str("pwd", &v))? {
+            "-P" => true,
+            "-L" => false,
+            option => return Err(env.other_err(env.sym.arg_err.clone(),
+                format!("pwd: {}: unknown option", option))),
+        }
+        None => false,
+    };
+    let dir = if physical {
+        env::current_dir().map_err(|e| env.other_err(env.sym.syscall_err.clone(),
+            format!("pwd: failed to get the current directory: detail={}", e)))?
+    } else {
+        env.logical_pwd("pwd")?
+    };
+    env.push(dir.to_str());
+    Ok(true)
+}
+fn push_dirs(env: &mut Env, name: &str, mode: Mode) -> Result<bool, Exception> {
+    let old_stack_len = env.arg_stack.len();
+    let pwd = env.logical_pwd(name)?;
+    env.push(pwd.to_str());
+    for dir in env.dir_stack.clone().into_iter().rev() {
+        env.push(dir.to_str());
+    }
+    env.stack_to_list(mode, old_stack_len);
+    Ok(true)
+}
+fn pushd(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let arg_n = env.arg_stack.len() - old_stack_len;
+    if arg_n > 1 {
+        return Err(env.argument_err("pushd", arg_n, "0 or 1"));
+    }
+    let dir = match env.arg_stack.pop_if(|_| arg_n == 1) {
+        Some(v) => {
+            let dir = v.to_path()
+                .map_err(|_| env.type_err_to_str("pushd", &v))?.into_owned();
+            cdpath_lookup(env, dir)
+        }
+        None => env.dir_stack.pop().ok_or_else(|| env.other_err(
+            env.sym.arg_err.clone(), "pushd: no other directory".to_string()))?,
+    };
+    let old = env.logical_pwd("pushd")?;
+    match env.change_dir("pushd", &dir) {
+        Ok(_) => env.dir_stack.push(old),
+        Err(e) => {
+            if arg_n == 0 {
+                env.dir_stack.push(dir);
+            }
+            return Err(e);
+        }
+    }
+    push_dirs(env, "pushd", mode)
+}
+fn popd(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    if ast.is_cell() {
+        return Err(env.argument_err("popd", ast.into_iter().count(), "0"));
+    }
+    let dir = env.dir_stack.pop().ok_or_else(|| env.other_err(
+        env.sym.arg_err.clone(), "popd: directory stack empty".to_string()))?;
+    if let Err(e) = env.change_dir("popd", &dir) {
+        env.dir_stack.push(dir);
+        return Err(e);
+    }
+    push_dirs(env, "popd", mode)
+}
+fn dirs(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    if ast.is_cell() {
+        return Err(env.argument_err("dirs", ast.into_iter().count(), "0"));
+    }
+    push_dirs(env, "dirs", mode)
+}
+fn in_dir(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let dir = ast.next().ok_or_else(|| env.argument_err("in-dir", 0, "1 or more"))?;
+    env.eval(Mode::Single, dir)?;
+    let dir = env.arg_stack.pop().unwrap();
+    let dir = dir.to_path()
+        .map_err(|_| env.type_err_to_str("in-dir", &dir))?.into_owned();
+    let old = env.logical_pwd("in-dir")?;
+    let old_oldpwd = env.var_os("OLDPWD");
+    env.change_dir("in-dir", &dir)?;
+    let result = progn(env, mode, ast);
+    env.change_dir("in-dir", &old)?;
+    env.set_var("OLDPWD", old_oldpwd);
+    result
+}
+fn env_vars(env: &mut Env, _: Mode, ast: &Val) -> Result<bool, Exception> {
+    if ast.is_cell() {
+        return Err(env.argument_err("env-vars", ast.into_iter().count(), "0"));
+    }
+    let result = Val::new_dict();
+    for (name, value) in env.vars_os() {
+        result.dict().insert(name.into(), PathBuf::from(value).to_str());
+    }
+    for (name, value) in env.exported_vars()? {
+        result.dict().insert(name, value.to_str());
+    }
+    env.push(result);
+    Ok(true)
+}
+fn env_unset(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    if env.arg_stack.len() == old_stack_len {
+        return Err(env.argument_err("env-unset", 0, "1 or more"));
+    }
+    let mut found = true;
+    for i in old_stack_len..env.arg_stack.len() {
+        let var = env.arg_stack[i].clone();
+        let name = var.to_path()
+            .map_err(|_| env.type_err_to_str("env-unset", &var))?.into_owned();
+        env.arg_stack[i] = match env.var_os(&name) {
+            Some(value) => PathBuf::from(value).to_str(),
+            None => {
+                found = false;
+                env.nil()
+            }
+        };
+        env.set_var(name, None::<OsString>);
+    }
+    env.stack_to_list(mode, old_stack_len);
+    Ok(found)
+}
+fn with_env(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let mut ast = ast;
+    let bindings = ast.next().ok_or_else(|| env.argument_err("with-env", 0, "1 or more"))?;
+    let old_stack_len = env.eval_args(bindings)?;
+    if !(env.arg_stack.len() - old_stack_len).is_multiple_of(2) {
+        env.arg_stack.truncate(old_stack_len);
+        return Err(env.other_err(env.sym.arg_err.clone(),
+            "with-env: bindings must be pairs of a name and a value".to_string()));
+    }
+    let args: Vec<Val> = env.arg_stack.drain(old_stack_len..).collect();
+    let mut vars = vec![];
+    for pair in args.chunks(2) {
+        let name = pair[0].to_path()
+            .map_err(|_| env.type_err_to_str("with-env", &pair[0]))?.into_owned();
+        let value = if pair[1].is_nil() {
+            None
+        } else {
+            Some(pair[1].to_path()
+                .map_err(|_| env.type_err_to_str("with-env", &pair[1]))?.into_owned())
+        };
+        vars.push((name, value));
+    }
+    let outer: Vec<(OsString, Option<Option<OsString>>)> = vars.into_iter().map(|(name, value)| {
+        let name = name.into_os_string();
+        let old = env.env_overlay.insert(name.clone(), value.map(PathBuf::into_os_string));
+        (name, old)
+    }).collect();
+    let result = progn(env, mode, ast);
+    for (name, old) in outer.into_iter().rev() {
+        let _ = match old {
+            Some(value) => env.env_overlay.insert(name, value),
+            None => env.env_overlay.remove(&name),
+        };
+    }
+    result
+}
+fn export_args(env: &mut Env, name: &str, ast: &Val) -> Result<Vec<Val>, Exception> {
+    let old_stack_len = env.eval_args(ast)?;
+    let args: Vec<Val> = env.arg_stack.drain(old_stack_len..).collect();
+    let mut vars = vec![];
+    for v in &args {
+        let var = v.to_path()
+            .map_err(|_| env.type_err_to_str(name, v))?.into_owned().to_var();
+        vars.push(var);
     }
-    result
+    Ok(vars)
+}
+fn export(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let vars = export_args(env, "export", ast)?;
+    for var in vars {
+        if !env.exports.contains(&var) {
+            env.exports.push(var);
+        }
+    }
+    push_exports(env, mode);
+    Ok(true)
+}
+fn unexport(env: &mut Env, mode: Mode, ast: &Val) -> Result<bool, Exception> {
+    let vars = export_args(env, "unexport", ast)?;
+    env.exports.retain(|var| !vars.contains(var));
+    push_exports(env, mode);
+    Ok(true)
+}
+fn push_exports(env: &mut Env, mode: Mode) {
+    let old_stack_len = env.arg_stack.len();
+    for var in env.exports.clone() {
+        env.push(unsafe { (*var.var().name).clone() }.intern());
+    }
+    env.stack_to_list(mode, old_stack_len);
 }
 
 fn main() {
     let mut env = Env::new(1024, 1024);
+    run_args(&mut env);
+    env.trap_exit();
+}
+fn run_args(env: &mut Env) {
     let mut args = env::args().skip(1);
+    let mut script = false;
     while let Some(arg) = args.next() {
-        if arg == "-c" {
+        if arg == "-i" {
+            env.init_job_control();
+        } else if arg == "-c" {
+            script = true;
             if let Some(code) = args.next() {
                 let mut reader = PeekableReader::new(Cursor::new(code));
                 loop {
                     reader.skip_brank();
-                    match reader.parse(&mut env) {
+                    match reader.parse(env) {
                         Ok(Some(ast)) => {
                             println!("{}", ast);
+                            reader.skip_brank();
+                            if let Ok(None) = reader.peek() {
+                                env.set_exec_last(&ast);
+                            }
                             match env.eval(Mode::Single, &ast) {
                                 Ok(x) => {
                                     println!("{}", x);
                                     println!("{}", env.arg_stack.pop().unwrap());
                                 }
                                 Err(Exception::Other) => {
+                                    env.trap_error();
                                     println!("{}", env.arg_stack.pop().unwrap());
                                     println!("{}", env.arg_stack.pop().unwrap());
                                 }
@@ -4110,12 +6584,13 @@ fn main() {
                 }
             }
         } else {
+            script = true;
             match OpenOptions::new().read(true).write(true).create(true).open(&arg) {
                 Ok(fd) => {
                     let mut reader = PeekableReader::new(fd);
                     loop {
                         reader.skip_brank();
-                        match reader.parse(&mut env) {
+                        match reader.parse(env) {
                             Ok(Some(ast)) => {
                                 //println!("ast: {}", ast);
 
@@ -4123,6 +6598,7 @@ fn main() {
                                     Ok(Some(x)) => x,
                                     Ok(None) => ast,
                                     Err(Exception::Other) => {
+                                        env.trap_error();
                                         println!("{}", env.arg_stack.pop().unwrap());
                                         println!("{}", env.arg_stack.pop().unwrap());
                                         return;
@@ -4134,6 +6610,10 @@ fn main() {
                                 };
 
                                 println!("expanded: {}", expanded);
+                                reader.skip_brank();
+                                if let Ok(None) = reader.peek() {
+                                    env.set_exec_last(&expanded);
+                                }
                                 match env.eval(Mode::Single, &expanded) {
                                     Ok(x) => {
                                         //println!("{}", x);
@@ -4142,6 +6622,7 @@ fn main() {
                                         continue;
                                     }
                                     Err(Exception::Other) => {
+                                        env.trap_error();
                                         println!("{}", env.arg_stack.pop().unwrap());
                                         println!("{}", env.arg_stack.pop().unwrap());
                                     }
@@ -4166,4 +6647,1011 @@ fn main() {
             }
         }
     }
+    if !script {
+        repl(env);
+    }
+}
+fn is_incomplete(e: &ParseErr) -> bool {
+    matches!(e, ParseErr::Other(_, msg) if msg == "unexpected EOF" || msg.ends_with("is not terminated"))
+}
+fn prompt_string(name: &str, default: &str) -> String {
+    let val = name.to_var().var().val.clone();
+    match val.to_str() {
+        Ok(prompt) if !val.is_nil() => prompt.into_owned(),
+        _ => default.to_string(),
+    }
+}
+fn parse_all(env: &Env, text: &str) -> Result<Vec<Val>, ParseErr> {
+    let mut reader = PeekableReader::new(Cursor::new(text.to_string()));
+    let mut forms = vec![];
+    loop {
+        reader.skip_brank();
+        match reader.parse(env)? {
+            Some(ast) => forms.push(ast),
+            None => return match reader.peek()? {
+                Some(c) => Err(ParseErr::Syntax(reader.line(), c)),
+                None => Ok(forms),
+            }
+        }
+    }
+}
+fn repl(env: &mut Env) {
+    let interactive = unsafe { libc::isatty(0) } == 1;
+    if interactive && !env.job_control {
+        env.init_job_control();
+    }
+    let mut editor = if interactive && unsafe { libc::isatty(2) } == 1
+            && env.var_os("TERM").is_none_or(|term| term != "dumb") {
+        Some(LineEditor::new(env))
+    } else {
+        None
+    };
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() {
+            prompt_string("PS1", "shino> ")
+        } else {
+            prompt_string("PS2", "... ")
+        };
+        let mut line = String::new();
+        let read = match editor.as_mut() {
+            Some(editor) => {
+                editor.read_line(env, &prompt, &prompt_string("PS2", "... ")).map(|read| {
+                    if let Some(read) = read {
+                        line = read + "\n";
+                    }
+                    line.len()
+                })
+            }
+            None => {
+                if interactive {
+                    eprint!("{}", prompt);
+                    let _ = io::stderr().flush();
+                }
+                io::stdin().lock().read_line(&mut line)
+            }
+        };
+        let eof = match read {
+            Ok(n) => n == 0,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("{}: read error, {}", NAME, e);
+                return;
+            }
+        };
+        if eof && input.is_empty() {
+            if interactive {
+                eprintln!();
+            }
+            return;
+        }
+        input.push_str(&line);
+        if !env.traps.contains_key("SIGINT") {
+            PENDING_SIGNALS.fetch_and(!(1 << libc::SIGINT), Ordering::SeqCst);
+        }
+
+        match parse_all(env, &input) {
+            Err(e) if is_incomplete(&e) && !eof => continue,
+            Err(e) => eprintln!("{}", e),
+            Ok(forms) => {
+                for ast in forms {
+                    eval_interactive(env, &ast, interactive);
+                }
+            }
+        }
+        input.clear();
+        if eof {
+            return;
+        }
+    }
+}
+fn eval_interactive(env: &mut Env, ast: &Val, interactive: bool) {
+    let old_stack_len = env.arg_stack.len();
+    let result = match env.expand(ast) {
+        Ok(expanded) => {
+            let expanded = expanded.unwrap_or_else(|| ast.clone());
+            env.eval(Mode::Single, &expanded)
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => {
+            let result = env.arg_stack.pop().unwrap();
+            if interactive && !result.is_nil() {
+                let _ = writeln!(env.sym.stdout.var().val, "{}", result);
+            }
+            env.sym.ret.var().val = result;
+        }
+        Err(Exception::Other) => {
+            env.trap_error();
+            let msg = env.arg_stack.pop().unwrap();
+            let label = env.arg_stack.pop().unwrap();
+            let _ = writeln!(env.sym.stderr.var().val, "{}: {}", label, msg);
+        }
+        Err(e) => {
+            let _ = writeln!(env.sym.stderr.var().val, "{:?}", e);
+        }
+    }
+    let _ = env.sym.stdout.var().val.flush();
+    env.arg_stack.truncate(old_stack_len);
+}
+
+struct RawMode {
+    orig: libc::termios,
+    raw: libc::termios,
+}
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        unsafe {
+            let mut term: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(0, &mut term) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let orig = term;
+            term.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+            term.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+            term.c_cflag |= libc::CS8;
+            term.c_cc[libc::VMIN] = 1;
+            term.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(0, libc::TCSADRAIN, &term) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { orig, raw: term })
+        }
+    }
+    fn cooked<T>(&self, f: impl FnOnce() -> T) -> T {
+        unsafe {
+            libc::tcsetattr(0, libc::TCSADRAIN, &self.orig);
+        }
+        let result = f();
+        unsafe {
+            libc::tcsetattr(0, libc::TCSADRAIN, &self.raw);
+        }
+        result
+    }
+}
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(0, libc::TCSADRAIN, &self.orig);
+        }
+    }
+}
+#[derive(Clone, Copy, PartialEq)]
+enum Key {
+    Char(char),
+    Ctrl(char),
+    Meta(char),
+    Enter,
+    MetaEnter,
+    Tab,
+    Backspace,
+    Delete,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Unknown,
+}
+fn read_byte(timeout_ms: Option<libc::c_int>) -> io::Result<Option<u8>> {
+    if let Some(ms) = timeout_ms {
+        let mut fds = libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut fds, 1, ms) } <= 0 {
+            return Ok(None);
+        }
+    }
+    let mut b = 0u8;
+    loop {
+        match unsafe { libc::read(0, &mut b as *mut u8 as *mut libc::c_void, 1) } {
+            1 => return Ok(Some(b)),
+            0 => return Ok(None),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+fn read_key() -> io::Result<Option<Key>> {
+    let Some(b) = read_byte(None)? else {
+        return Ok(None);
+    };
+    Ok(Some(match b {
+        b'\r'|b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        8|127 => Key::Backspace,
+        27 => match read_byte(Some(50))? {
+            Some(b'['|b'O') => {
+                let mut seq = String::new();
+                while let Some(b) = read_byte(Some(50))? {
+                    seq.push(b as char);
+                    if (0x40..=0x7e).contains(&b) {
+                        break;
+                    }
+                }
+                match &*seq {
+                    "A" => Key::Up,
+                    "B" => Key::Down,
+                    "C" => Key::Right,
+                    "D" => Key::Left,
+                    "H"|"1~"|"7~" => Key::Home,
+                    "F"|"4~"|"8~" => Key::End,
+                    "3~" => Key::Delete,
+                    _ => Key::Unknown,
+                }
+            }
+            Some(b'\r') => Key::MetaEnter,
+            Some(127) => Key::Meta('\x7f'),
+            Some(b) => Key::Meta(b as char),
+            None => Key::Unknown,
+        },
+        1..=26 => Key::Ctrl((b'a' + b - 1) as char),
+        0..=31 => Key::Unknown,
+        _ => {
+            let len = match b {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![b];
+            for _ in 1..len {
+                bytes.extend(read_byte(Some(50))?);
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Unknown,
+            }
+        }
+    }))
+}
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115f | 0x2e80..=0x303e | 0x3041..=0x33ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf | 0xac00..=0xd7a3 | 0xf900..=0xfaff | 0xfe30..=0xfe4f | 0xff00..=0xff60
+        | 0xffe0..=0xffe6 | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+fn str_width(s: &str) -> usize {
+    let mut width = 0;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            width += char_width(c);
+        }
+    }
+    width
+}
+fn term_cols() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(2, libc::TIOCGWINSZ, &mut size) } == -1 || size.ws_col == 0 {
+        80
+    } else {
+        size.ws_col as usize
+    }
+}
+#[derive(Clone, Copy, PartialEq)]
+enum Lex {
+    Code,
+    Quote,
+    Literal,
+    Comment,
+}
+fn scan(buf: &[char], pos: usize) -> (Vec<Lex>, bool) {
+    let mut lexes = Vec::with_capacity(pos);
+    let (mut quoted, mut comment, mut escaped) = (false, false, false);
+    for (i, &c) in buf[..pos].iter().enumerate() {
+        let lex = if escaped {
+            escaped = false;
+            Lex::Literal
+        } else if comment {
+            comment = c != '\n';
+            Lex::Comment
+        } else if quoted {
+            quoted = c != '\'';
+            if quoted { Lex::Literal } else { Lex::Quote }
+        } else {
+            match c {
+                '\\' => {
+                    escaped = true;
+                    Lex::Quote
+                }
+                '\'' => {
+                    quoted = true;
+                    Lex::Quote
+                }
+                ';' if i == 0 || buf[i - 1].is_whitespace() => {
+                    comment = true;
+                    Lex::Comment
+                }
+                _ => Lex::Code,
+            }
+        };
+        lexes.push(lex);
+    }
+    (lexes, quoted || comment || escaped)
+}
+fn matching_paren(buf: &[char], pos: usize) -> Option<usize> {
+    if pos == 0 || !matches!(buf[pos - 1], ')'|'}'|']') {
+        return None;
+    }
+    let (lexes, _) = scan(buf, pos);
+    if lexes[pos - 1] != Lex::Code {
+        return None;
+    }
+    let mut opens = vec![];
+    for (i, lex) in lexes.into_iter().enumerate() {
+        match (lex, buf[i]) {
+            (Lex::Code, '('|'{'|'[') => opens.push(i),
+            (Lex::Code, ')'|'}'|']') => {
+                let open = opens.pop();
+                if i == pos - 1 {
+                    return open;
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+fn completion_context(buf: &[char], pos: usize) -> Option<(Vec<String>, usize, String)> {
+    let (lexes, inside) = scan(buf, pos);
+    if inside {
+        return None;
+    }
+    let mut forms: Vec<(usize, Vec<String>)> = vec![(0, vec![])];
+    let mut word: Option<(usize, String)> = None;
+    fn end_word(word: &mut Option<(usize, String)>, forms: &mut [(usize, Vec<String>)]) {
+        if let Some((_, w)) = word.take() {
+            forms.last_mut().unwrap().1.push(w);
+        }
+    }
+    for (i, lex) in lexes.into_iter().enumerate() {
+        let c = buf[i];
+        match lex {
+            Lex::Quote => {
+                word.get_or_insert((i, String::new()));
+            }
+            Lex::Literal => word.get_or_insert((i, String::new())).1.push(c),
+            Lex::Comment if c == ';' => end_word(&mut word, &mut forms),
+            Lex::Comment => {}
+            Lex::Code => match c {
+                '$' => {
+                    end_word(&mut word, &mut forms);
+                    word = Some((i, "$".to_string()));
+                }
+                '(' | '{' => {
+                    end_word(&mut word, &mut forms);
+                    forms.push((i, vec![]));
+                }
+                ')' | '}' => {
+                    end_word(&mut word, &mut forms);
+                    if forms.len() > 1 {
+                        let (open, _) = forms.pop().unwrap();
+                        forms.last_mut().unwrap().1.push(buf[open..=i].iter().collect());
+                    }
+                }
+                '|' | '&' | ';' => {
+                    word = None;
+                    forms.last_mut().unwrap().1.clear();
+                }
+                '\n' if forms.len() == 1 || buf[forms.last().unwrap().0] == '{' => {
+                    word = None;
+                    forms.last_mut().unwrap().1.clear();
+                }
+                '<' | '>' => {
+                    end_word(&mut word, &mut forms);
+                    forms.last_mut().unwrap().1.push(c.to_string());
+                }
+                _ if c.is_whitespace() || c == '`' => end_word(&mut word, &mut forms),
+                _ => word.get_or_insert((i, String::new())).1.push(c),
+            },
+        }
+    }
+    let (start, word) = word.unwrap_or((pos, String::new()));
+    Some((forms.pop().unwrap().1, start, word))
+}
+fn escape_word(word: &str) -> String {
+    let mut escaped = String::new();
+    for c in word.chars() {
+        if c.is_whitespace() || "#$'();`^~?*[|&{}<>\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+fn path_candidates(word: &str, executable: bool) -> Vec<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let options = glob::MatchOptions { require_literal_leading_dot: true, ..glob::MatchOptions::new() };
+    let Ok(paths) = glob::glob_with(&(Pattern::escape(word) + "*"), options) else {
+        return vec![];
+    };
+    paths.filter_map(Result::ok).filter_map(|path| {
+        let meta = std::fs::metadata(&path).ok()?;
+        if executable && !meta.is_dir() && meta.permissions().mode() & 0o111 == 0 {
+            return None;
+        }
+        let mut candidate = path.to_string_lossy().into_owned();
+        if word.starts_with("./") && !candidate.starts_with("./") {
+            candidate.insert_str(0, "./");
+        }
+        if meta.is_dir() {
+            candidate.push('/');
+        }
+        Some(candidate)
+    }).collect()
+}
+fn command_candidates(env: &Env, word: &str) -> Vec<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut candidates = vec![];
+    for dir in env::split_paths(&env.var_os("PATH").unwrap_or_default()) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(word) && std::fs::metadata(entry.path())
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0) {
+                candidates.push(escape_word(&name));
+            }
+        }
+    }
+    candidates
+}
+fn completions(env: &mut Env, raw: &RawMode, buf: &[char], pos: usize)
+    -> Result<Option<(usize, Vec<String>)>, String>
+{
+    let Some((words, start, word)) = completion_context(buf, pos) else {
+        return Ok(None);
+    };
+    let symbols: Vec<(String, Val)> = SYM_TABLE.with(|tab| tab.borrow().iter()
+        .map(|(name, sym)| (name.to_string_lossy().into_owned(), sym.clone())).collect());
+    let mut candidates = vec![];
+    if let Some(name) = word.strip_prefix('$') {
+        for (var, sym) in symbols {
+            if var.starts_with(name) && !sym.remove_tag(SYM).var().val.is_nil() {
+                candidates.push(format!("${}", var));
+            }
+        }
+    } else if words.is_empty() && !word.contains('/') {
+        for (name, sym) in symbols {
+            if name.starts_with(&word) && !sym.sym().func.is_nil() {
+                candidates.push(name);
+            }
+        }
+        candidates.extend(command_candidates(env, &word));
+    } else {
+        let mut found = env.nil();
+        if let Some(code) = words.first().and_then(|name| env.completers.get(name)).cloned() {
+            let mut args = vec![word.as_str().intern()];
+            args.extend(words[1..].iter().map(|w| w.as_str().intern()));
+            let len = env.arg_stack.len();
+            found = match raw.cooked(|| env.run_trap(&code, &args)) {
+                Ok(found) => found,
+                Err(Exception::Other) => {
+                    let msg = env.arg_stack.pop().unwrap();
+                    let label = env.arg_stack.pop().unwrap();
+                    env.arg_stack.truncate(len);
+                    return Err(format!("{}: {}", label, msg));
+                }
+                Err(e) => {
+                    env.arg_stack.truncate(len);
+                    return Err(format!("{:?}", e));
+                }
+            };
+        }
+        let found: Vec<String> = if found.is_nil() {
+            path_candidates(&word, words.is_empty())
+        } else if found.is_cell() {
+            (&found).into_iter().filter_map(|v| v.to_str().ok().map(|s| s.into_owned())).collect()
+        } else {
+            found.to_str().ok().map(|s| s.into_owned()).into_iter().collect()
+        };
+        candidates = found.iter().filter(|s| s.starts_with(&word)).map(|s| escape_word(s)).collect();
+    }
+    candidates.sort();
+    candidates.dedup();
+    Ok(Some((start, candidates)))
+}
+fn escape_history(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+fn unescape_history(line: &str) -> String {
+    let mut entry = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => entry.push('\n'),
+                Some(c) => entry.push(c),
+                None => entry.push('\\'),
+            },
+            (c, false) => entry.push(c),
+        }
+    }
+    entry
+}
+fn config_var(env: &Env, name: &str) -> Option<String> {
+    let val = name.to_var().var().val.clone();
+    if !val.is_nil() {
+        if let Ok(s) = val.to_str() {
+            return Some(s.into_owned());
+        }
+    }
+    env.var_os(name).and_then(|s| s.into_string().ok())
+}
+const DEFAULT_HISTSIZE: usize = 1000;
+#[derive(Default)]
+struct Entry {
+    buf: Vec<char>,
+    pos: usize,
+    index: usize,
+    draft: Vec<char>,
+}
+struct LineEditor {
+    history: Vec<String>,
+    kill: String,
+    cursor_row: usize,
+}
+impl LineEditor {
+    fn new(env: &Env) -> LineEditor {
+        let history = Self::history_file(env)
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(unescape_history).collect())
+            .unwrap_or_default();
+        LineEditor { history, kill: String::new(), cursor_row: 0 }
+    }
+    fn history_file(env: &Env) -> Option<PathBuf> {
+        config_var(env, "HISTFILE").map(PathBuf::from)
+            .or_else(|| env.var_os("HOME").map(|home| Path::new(&home).join(".shino_history")))
+    }
+    fn history_size(env: &Env) -> usize {
+        config_var(env, "HISTSIZE").and_then(|n| n.parse().ok()).unwrap_or(DEFAULT_HISTSIZE)
+    }
+    fn push_history(&mut self, entry: &str, size: usize) -> bool {
+        if entry.trim().is_empty() || self.history.last().is_some_and(|last| last == entry) {
+            return false;
+        }
+        self.history.push(entry.to_string());
+        if self.history.len() > size {
+            self.history.drain(..self.history.len() - size);
+        }
+        true
+    }
+    fn add_history(&mut self, env: &Env, entry: &str) {
+        use std::os::unix::fs::OpenOptionsExt;
+        if !self.push_history(entry, Self::history_size(env)) {
+            return;
+        }
+        if let Some(path) = Self::history_file(env) {
+            let text: String = self.history.iter().map(|entry| escape_history(entry) + "\n").collect();
+            let _ = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+                .open(path).and_then(|mut file| file.write_all(text.as_bytes()));
+        }
+    }
+    fn refresh(&mut self, prompt: &str, prompt2: &str, buf: &[char], pos: usize) -> io::Result<()> {
+        let cols = term_cols();
+        let mut out = String::new();
+        if self.cursor_row > 0 {
+            out += &format!("\x1b[{}A", self.cursor_row);
+        }
+        out += "\r\x1b[J";
+        out += prompt;
+        let (mut row, mut col) = (0, str_width(prompt) % cols);
+        let mut cursor = (0, col);
+        let matching = matching_paren(buf, pos);
+        for i in 0..=buf.len() {
+            let c = buf.get(i).copied();
+            let width = c.map_or(0, char_width);
+            // the terminal wraps before a character that does not fit; at the end it waits
+            // at the last column, so the line is ended by hand
+            if c.is_none() && col >= cols {
+                out += "\r\n";
+                row += 1;
+                col = 0;
+            } else if c.is_some_and(|c| c != '\n') && col + width > cols {
+                row += 1;
+                col = 0;
+            }
+            if i == pos {
+                cursor = (row, col);
+            }
+            match c {
+                None => {}
+                Some('\n') => {
+                    out += "\r\n";
+                    out += prompt2;
+                    row += 1;
+                    col = str_width(prompt2) % cols;
+                }
+                Some(c) if matching == Some(i) => {
+                    out += &format!("\x1b[7m{}\x1b[0m", c);
+                    col += width;
+                }
+                Some(c) => {
+                    out.push(c);
+                    col += width;
+                }
+            }
+        }
+        if row > cursor.0 {
+            out += &format!("\x1b[{}A", row - cursor.0);
+        }
+        out += "\r";
+        if cursor.1 > 0 {
+            out += &format!("\x1b[{}C", cursor.1);
+        }
+        self.cursor_row = cursor.0;
+        let mut stderr = io::stderr();
+        stderr.write_all(out.as_bytes())?;
+        stderr.flush()
+    }
+    fn search(&mut self, prompt2: &str, buf: &mut Vec<char>, pos: &mut usize, index: &mut usize)
+        -> io::Result<Option<Key>>
+    {
+        let (orig_buf, orig_pos) = (buf.clone(), *pos);
+        let mut query = String::new();
+        let mut start = self.history.len();
+        loop {
+            let found = if query.is_empty() {
+                None
+            } else {
+                (0..start).rev().find_map(|i| self.history[i].find(&query).map(|at| (i, at)))
+            };
+            let failed = if found.is_none() && !query.is_empty() { "failed " } else { "" };
+            let prompt = format!("({}reverse-i-search)`{}': ", failed, query);
+            if let Some((i, at)) = found {
+                *buf = self.history[i].chars().collect();
+                *pos = self.history[i][..at].chars().count();
+            }
+            self.refresh(&prompt, prompt2, buf, *pos)?;
+            match read_key()? {
+                Some(Key::Ctrl('r')) => start = found.map_or(start, |(i, _)| i),
+                Some(Key::Char(c)) => {
+                    query.push(c);
+                    start = found.map_or(self.history.len(), |(i, _)| i + 1);
+                }
+                Some(Key::Backspace) => {
+                    let _ = query.pop();
+                    start = self.history.len();
+                }
+                Some(Key::Ctrl('g')) | None => {
+                    *buf = orig_buf;
+                    *pos = orig_pos;
+                    return Ok(None);
+                }
+                Some(key) => {
+                    if let Some((i, _)) = found {
+                        *index = i;
+                    }
+                    return Ok(Some(key));
+                }
+            }
+        }
+    }
+    fn show_candidates(&mut self, candidates: &[String]) -> io::Result<()> {
+        let cols = term_cols();
+        let width = candidates.iter().map(|c| str_width(c)).max().unwrap_or(0) + 2;
+        let per_row = (cols / width).max(1);
+        let rows = candidates.len().div_ceil(per_row);
+        let mut out = String::new();
+        for row in 0..rows {
+            out += "\r\n";
+            for candidate in candidates.iter().skip(row).step_by(rows) {
+                out += &format!("{:1$}", candidate, width - str_width(candidate) + candidate.len());
+            }
+        }
+        out += "\r\n";
+        self.cursor_row = 0;
+        let mut stderr = io::stderr();
+        stderr.write_all(out.as_bytes())?;
+        stderr.flush()
+    }
+    fn edit(&mut self, entry: &mut Entry, key: Key) -> bool {
+        let Entry { buf, pos, index, draft } = entry;
+        let line_start = |buf: &[char], pos: usize| buf[..pos].iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+        let line_end = |buf: &[char], pos: usize| buf[pos..].iter().position(|&c| c == '\n').map_or(buf.len(), |i| pos + i);
+        let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+        match key {
+            Key::Char(c) => {
+                buf.insert(*pos, c);
+                *pos += 1;
+            }
+            Key::MetaEnter => {
+                buf.insert(*pos, '\n');
+                *pos += 1;
+            }
+            Key::Ctrl('d') | Key::Delete => {
+                if *pos < buf.len() {
+                    buf.remove(*pos);
+                }
+            }
+            Key::Backspace | Key::Ctrl('h') => {
+                if *pos > 0 {
+                    *pos -= 1;
+                    buf.remove(*pos);
+                }
+            }
+            Key::Ctrl('a') | Key::Home => *pos = line_start(buf, *pos),
+            Key::Ctrl('e') | Key::End => *pos = line_end(buf, *pos),
+            Key::Ctrl('b') | Key::Left => *pos = pos.saturating_sub(1),
+            Key::Ctrl('f') | Key::Right => *pos = (*pos + 1).min(buf.len()),
+            Key::Meta('b') => {
+                while *pos > 0 && !is_word(buf[*pos - 1]) {
+                    *pos -= 1;
+                }
+                while *pos > 0 && is_word(buf[*pos - 1]) {
+                    *pos -= 1;
+                }
+            }
+            Key::Meta('f') => {
+                while *pos < buf.len() && !is_word(buf[*pos]) {
+                    *pos += 1;
+                }
+                while *pos < buf.len() && is_word(buf[*pos]) {
+                    *pos += 1;
+                }
+            }
+            Key::Ctrl('k') => {
+                let end = line_end(buf, *pos);
+                let end = if end == *pos && end < buf.len() { end + 1 } else { end };
+                self.kill = buf.drain(*pos..end).collect();
+            }
+            Key::Ctrl('u') => {
+                let start = line_start(buf, *pos);
+                self.kill = buf.drain(start..*pos).collect();
+                *pos = start;
+            }
+            Key::Ctrl('w') | Key::Meta('\x7f') => {
+                let mut start = *pos;
+                while start > 0 && buf[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !buf[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.kill = buf.drain(start..*pos).collect();
+                *pos = start;
+            }
+            Key::Meta('d') => {
+                let mut end = *pos;
+                while end < buf.len() && !is_word(buf[end]) {
+                    end += 1;
+                }
+                while end < buf.len() && is_word(buf[end]) {
+                    end += 1;
+                }
+                self.kill = buf.drain(*pos..end).collect();
+            }
+            Key::Ctrl('y') => {
+                for c in self.kill.chars() {
+                    buf.insert(*pos, c);
+                    *pos += 1;
+                }
+            }
+            Key::Ctrl('t') => {
+                if *pos > 0 && buf.len() > 1 {
+                    let at = if *pos == buf.len() { *pos - 1 } else { *pos };
+                    buf.swap(at - 1, at);
+                    *pos = at + 1;
+                }
+            }
+            Key::Ctrl('p') | Key::Up => {
+                let start = line_start(buf, *pos);
+                if start > 0 {
+                    let prev = line_start(buf, start - 1);
+                    *pos = (prev + *pos - start).min(start - 1);
+                } else if *index > 0 {
+                    if *index == self.history.len() {
+                        *draft = buf.clone();
+                    }
+                    *index -= 1;
+                    *buf = self.history[*index].chars().collect();
+                    *pos = buf.len();
+                }
+            }
+            Key::Ctrl('n') | Key::Down => {
+                let end = line_end(buf, *pos);
+                if end < buf.len() {
+                    let next = end + 1;
+                    *pos = (next + *pos - line_start(buf, *pos)).min(line_end(buf, next));
+                } else if *index < self.history.len() {
+                    *index += 1;
+                    *buf = if *index == self.history.len() {
+                        std::mem::take(draft)
+                    } else {
+                        self.history[*index].chars().collect()
+                    };
+                    *pos = buf.len();
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+    fn read_line(&mut self, env: &mut Env, prompt: &str, prompt2: &str) -> io::Result<Option<String>> {
+        let raw = RawMode::enable()?;
+        let mut entry = Entry { index: self.history.len(), ..Entry::default() };
+        let mut pending = None;
+        self.cursor_row = 0;
+        loop {
+            self.refresh(prompt, prompt2, &entry.buf, entry.pos)?;
+            let key = match pending.take() {
+                Some(key) => key,
+                None => match read_key()? {
+                    Some(key) => key,
+                    None if entry.buf.is_empty() => return Ok(None),
+                    None => Key::Enter,
+                }
+            };
+            let Entry { buf, pos, index, .. } = &mut entry;
+            match key {
+                Key::Enter => {
+                    let text: String = buf.iter().collect();
+                    if !parse_all(env, &text).is_err_and(|e| is_incomplete(&e)) {
+                        self.refresh(prompt, prompt2, buf, buf.len())?;
+                        eprintln!();
+                        drop(raw);
+                        self.add_history(env, &text);
+                        return Ok(Some(text));
+                    }
+                    buf.insert(*pos, '\n');
+                    *pos += 1;
+                }
+                Key::Ctrl('c') => {
+                    self.refresh(prompt, prompt2, buf, buf.len())?;
+                    eprintln!("^C");
+                    return Ok(Some(String::new()));
+                }
+                Key::Ctrl('d') if buf.is_empty() => return Ok(None),
+                Key::Ctrl('l') => {
+                    eprint!("\x1b[H\x1b[2J");
+                    self.cursor_row = 0;
+                }
+                Key::Ctrl('r') => pending = self.search(prompt2, buf, pos, index)?,
+                Key::Tab => {
+                    let (start, candidates) = match completions(env, &raw, buf, *pos) {
+                        Ok(Some(found)) => found,
+                        Ok(None) => continue,
+                        Err(msg) => {
+                            self.refresh(prompt, prompt2, buf, buf.len())?;
+                            eprint!("\r\n{}\r\n", msg);
+                            self.cursor_row = 0;
+                            continue;
+                        }
+                    };
+                    let typed = *pos - start;
+                    let common = candidates.iter().skip(1).fold(candidates.first().cloned().unwrap_or_default(),
+                        |common, c| common.chars().zip(c.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a).collect());
+                    let text = match candidates.len() {
+                        0 => continue,
+                        1 if !common.ends_with('/') => common + " ",
+                        1 => common,
+                        _ if common.chars().count() > typed => common,
+                        _ => {
+                            self.refresh(prompt, prompt2, buf, buf.len())?;
+                            self.show_candidates(&candidates)?;
+                            continue;
+                        }
+                    };
+                    let len = text.chars().count();
+                    let _ = buf.splice(start..*pos, text.chars());
+                    *pos = start + len;
+                }
+                key => {
+                    let _ = self.edit(&mut entry, key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+    fn editor(history: &[&str]) -> LineEditor {
+        LineEditor { history: history.iter().map(|s| s.to_string()).collect(), kill: String::new(), cursor_row: 0 }
+    }
+    fn type_keys(editor: &mut LineEditor, keys: Vec<Key>) -> Entry {
+        let mut entry = Entry { index: editor.history.len(), ..Entry::default() };
+        for key in keys {
+            assert!(editor.edit(&mut entry, key));
+        }
+        entry
+    }
+    fn text(s: &str) -> Vec<Key> {
+        s.chars().map(Key::Char).collect()
+    }
+
+    #[test]
+    fn history_escape_round_trip() {
+        for entry in ["(show 1)", "(echo a\n  b)", "a\\nb", "trailing\\"] {
+            let line = escape_history(entry);
+            assert!(!line.contains('\n'));
+            assert_eq!(unescape_history(&line), entry);
+        }
+        assert_eq!(unescape_history("a\\"), "a\\");
+    }
+
+    #[test]
+    fn history_size_and_repeats() {
+        let mut editor = editor(&[]);
+        assert!(editor.push_history("(show 1)", 2));
+        assert!(!editor.push_history("(show 1)", 2));
+        assert!(!editor.push_history("  ", 2));
+        assert!(editor.push_history("(show 2)", 2));
+        assert!(editor.push_history("(show 3)", 2));
+        assert_eq!(editor.history, ["(show 2)", "(show 3)"]);
+    }
+
+    #[test]
+    fn paren_matching() {
+        let buf = chars("(echo (a b) ')' ; (\n)");
+        assert_eq!(matching_paren(&buf, 11), Some(6));
+        assert_eq!(matching_paren(&buf, 15), None);
+        assert_eq!(matching_paren(&buf, buf.len()), Some(0));
+        assert_eq!(matching_paren(&chars("{a [b]}"), 7), Some(0));
+        assert_eq!(matching_paren(&chars("a)"), 2), None);
+        assert_eq!(matching_paren(&chars("(a"), 2), None);
+    }
+
+    #[test]
+    fn completion_contexts() {
+        let context = |s: &str| completion_context(&chars(s), s.chars().count());
+        let words = |ws: &[&str]| ws.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(context("(wait-p"), Some((vec![], 1, "wait-p".to_string())));
+        assert_eq!(context("(ls /tmp/sub\\ d"), Some((words(&["ls"]), 4, "/tmp/sub d".to_string())));
+        assert_eq!(context("(echo $shino_v"), Some((words(&["echo"]), 6, "$shino_v".to_string())));
+        assert_eq!(context("(frob (a b) 'x y' al"), Some((words(&["frob", "(a b)", "x y"]), 18, "al".to_string())));
+        assert_eq!(context("(echo a | wait-p"), Some((vec![], 10, "wait-p".to_string())));
+        assert_eq!(context("(echo 'abc"), None);
+        assert_eq!(context("(echo ; x"), None);
+    }
+
+    #[test]
+    fn editing_keys() {
+        let mut editor = editor(&[]);
+        let mut keys = text("(echo foo bar)");
+        keys.extend([Key::Left, Key::Ctrl('w'), Key::Ctrl('a'), Key::Meta('f'), Key::Ctrl('y')]);
+        let entry = type_keys(&mut editor, keys);
+        assert_eq!(entry.buf, chars("(echobar foo )"));
+        assert_eq!(entry.pos, 8);
+
+        let entry = type_keys(&mut editor, [text("ab"), vec![Key::Ctrl('t'), Key::Backspace, Key::Ctrl('b'), Key::Delete]].concat());
+        assert!(entry.buf.is_empty());
+
+        let entry = type_keys(&mut editor, [text("(a"), vec![Key::MetaEnter], text("b)"), vec![Key::Up, Key::Ctrl('k')]].concat());
+        assert_eq!(entry.buf, chars("(ab)"));
+        assert_eq!(editor.kill, "\n");
+        assert!(!editor.edit(&mut Entry::default(), Key::Tab));
+    }
+
+    #[test]
+    fn history_keys() {
+        let mut editor = editor(&["(show 1)", "(show 2)"]);
+        let entry = type_keys(&mut editor, [text("new"), vec![Key::Up, Key::Up, Key::Up]].concat());
+        assert_eq!(entry.buf, chars("(show 1)"));
+        let mut entry = entry;
+        for key in [Key::Down, Key::Ctrl('e'), Key::Backspace, Key::Down] {
+            assert!(editor.edit(&mut entry, key));
+        }
+        assert_eq!(entry.buf, chars("new"));
+        assert_eq!(entry.pos, 3);
+    }
 }
@@ -0,0 +1,38 @@
+# helpers for test.sn to drive the interactive interpreter on a pseudo terminal
+# usage: . ./pty.sh; pty_start [NAME=value...]; pty_send keys; pty_wait file [pattern]; pty_stop
+
+pty_dir=$(mktemp -d)
+
+# starts the interpreter that runs test.sn under script and waits for its first prompt
+pty_start() {
+    mkfifo "$pty_dir/in"
+    env "$@" timeout 10 script -qc /proc/$PPID/exe /dev/null < "$pty_dir/in" > "$pty_dir/out" &
+    pty_pid=$!
+    exec 3> "$pty_dir/in"
+    pty_wait "$pty_dir/out" 'shino> '
+}
+
+# types keys given as a printf format
+pty_send() {
+    printf "$1" >&3
+}
+
+# waits until file exists and contains pattern, failing after about five seconds
+pty_wait() {
+    i=0
+    until [ -e "$1" ] && grep -q -e "${2-}" "$1"; do
+        i=$((i + 1))
+        [ $i -le 100 ] || return 1
+        sleep 0.05
+    done
+}
+
+# ends the session with Ctrl-D and waits for the interpreter to exit
+pty_stop() {
+    printf '\004' >&3
+    exec 3>&-
+    wait $pty_pid
+    status=$?
+    rm -rf "$pty_dir"
+    return $status
+}